@@ -0,0 +1,448 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use serde::Deserialize;
+use serde_bencode::value::Value;
+
+use crate::error::{Error, Result};
+
+macro_rules! bad {
+    ($($arg:tt)*) => {
+        Error::Bencode(format!($($arg)*))
+    };
+}
+
+// lists and dictionaries nested deeper than this are refused rather than
+// recursed into, so hostile input can't overflow the stack
+pub const MAX_DEPTH: usize = 256;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeyPolicy {
+    // reject unsorted or duplicate dictionary keys
+    Strict,
+    // sort keys, and on duplicates keep the first value seen
+    KeepFirst,
+    // sort keys, and on duplicates keep the last value seen
+    KeepLast,
+}
+
+// re-emits `buf` with every dictionary's keys in canonical (sorted, unique)
+// order and integers without leading zeros, returning the problems that had to
+// be fixed along the way. in strict mode the first problem is an error instead,
+// naming the offending key path. integers that don't fit in an i64 are always
+// an error.
+pub fn normalize(buf: &[u8], policy: KeyPolicy) -> Result<(Vec<u8>, Vec<String>)> {
+    let mut fixes = vec![];
+    let (out, end) = canonical(buf, 0, 0, "", policy, &mut fixes)?;
+    if end != buf.len() {
+        return Err(bad!(
+            "{} trailing bytes after bencoded value",
+            buf.len() - end
+        ));
+    }
+    Ok((out, fixes))
+}
+
+// the byte range of `key`'s value in the top-level dictionary of `buf`,
+// exactly as it appears rather than re-encoded. duplicate keys resolve the same
+// way `normalize` would under `policy`.
+pub fn raw_value(buf: &[u8], key: &[u8], policy: KeyPolicy) -> Result<Option<Range<usize>>> {
+    if buf.first() != Some(&b'd') {
+        return Err(bad!("top-level value is not a dictionary"));
+    }
+    let mut found = None;
+    let mut pos = 1;
+    while buf.get(pos) != Some(&b'e') {
+        let (k, end) = string(buf, pos)?;
+        let (_, value_end) = canonical(buf, end, 1, "", KeyPolicy::KeepLast, &mut vec![])?;
+        if k == key && !(found.is_some() && policy == KeyPolicy::KeepFirst) {
+            found = Some(end..value_end);
+        }
+        pos = value_end;
+    }
+    Ok(found)
+}
+
+// the length of the single bencoded value at the start of `buf`, for payloads
+// where raw bytes follow a bencoded header
+pub fn value_len(buf: &[u8]) -> Result<usize> {
+    let (_, end) = canonical(buf, 0, 0, "", KeyPolicy::KeepLast, &mut vec![])?;
+    Ok(end)
+}
+
+// serde_bencode::from_bytes for input from the network, whose decoder recurses
+// without limit: anything nested deeper than MAX_DEPTH is refused first
+pub fn decode<'de, T: Deserialize<'de>>(buf: &'de [u8]) -> Result<T> {
+    value_len(buf)?;
+    serde_bencode::from_bytes(buf).map_err(|e| Error::Bencode(e.to_string()))
+}
+
+// the canonical encoding of a decoded value: dictionary keys sorted by their
+// raw bytes, so anything built or edited in memory encodes the same way a
+// well-formed file would
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = vec![];
+    encode_into(value, &mut out);
+    out
+}
+
+fn encode_into(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Int(i) => out.extend(format!("i{}e", i).into_bytes()),
+        Value::Bytes(b) => encode_bytes(b, out),
+        Value::List(items) => {
+            out.push(b'l');
+            for item in items {
+                encode_into(item, out);
+            }
+            out.push(b'e');
+        }
+        Value::Dict(entries) => {
+            let mut keys: Vec<&Vec<u8>> = entries.keys().collect();
+            keys.sort();
+            out.push(b'd');
+            for key in keys {
+                encode_bytes(key, out);
+                encode_into(&entries[key], out);
+            }
+            out.push(b'e');
+        }
+    }
+}
+
+fn encode_bytes(b: &[u8], out: &mut Vec<u8>) {
+    out.extend(b.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(b);
+}
+
+// `depth` is how many lists and dictionaries the value at `pos` is inside
+fn canonical(
+    buf: &[u8],
+    pos: usize,
+    depth: usize,
+    path: &str,
+    policy: KeyPolicy,
+    fixes: &mut Vec<String>,
+) -> Result<(Vec<u8>, usize)> {
+    if depth >= MAX_DEPTH && matches!(buf.get(pos), Some(b'l' | b'd')) {
+        return Err(bad!(
+            "nested more than {} levels deep at {}",
+            MAX_DEPTH,
+            path_or_root(path)
+        ));
+    }
+    match buf.get(pos) {
+        Some(b'i') => {
+            let end = find(buf, pos, b'e')?;
+            let digits = std::str::from_utf8(&buf[pos + 1..end])
+                .map_err(|_| bad!("integer at {} is not ASCII", path_or_root(path)))?;
+            let value: i64 = digits
+                .parse()
+                .ok()
+                .filter(|_| !digits.starts_with('+'))
+                .ok_or_else(|| {
+                    bad!(
+                        "integer {:?} at {} is malformed or does not fit in 64 bits",
+                        digits,
+                        path_or_root(path)
+                    )
+                })?;
+            if value.to_string() != digits {
+                if policy == KeyPolicy::Strict {
+                    return Err(bad!(
+                        "non-canonical integer {:?} at {}",
+                        digits,
+                        path_or_root(path)
+                    ));
+                }
+                fixes.push(format!(
+                    "non-canonical integer {:?} at {}",
+                    digits,
+                    path_or_root(path)
+                ));
+            }
+            Ok((format!("i{}e", value).into_bytes(), end + 1))
+        }
+        Some(b'0'..=b'9') => {
+            let (_, end) = string(buf, pos)?;
+            Ok((buf[pos..end].to_vec(), end))
+        }
+        Some(b'l') => {
+            let mut out = vec![b'l'];
+            let mut pos = pos + 1;
+            let mut idx = 0;
+            while buf.get(pos) != Some(&b'e') {
+                let (item, end) = canonical(
+                    buf,
+                    pos,
+                    depth + 1,
+                    &format!("{}[{}]", path, idx),
+                    policy,
+                    fixes,
+                )?;
+                out.extend(item);
+                pos = end;
+                idx += 1;
+            }
+            out.push(b'e');
+            Ok((out, pos + 1))
+        }
+        Some(b'd') => {
+            let mut entries: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+            let mut prev: Option<Vec<u8>> = None;
+            let mut pos = pos + 1;
+            while buf.get(pos) != Some(&b'e') {
+                if !buf.get(pos).is_some_and(u8::is_ascii_digit) {
+                    return Err(bad!(
+                        "dictionary key at {} is not a string",
+                        path_or_root(path)
+                    ));
+                }
+                let (key, end) = string(buf, pos)?;
+                let key = key.to_vec();
+                let key_path = if path.is_empty() {
+                    String::from_utf8_lossy(&key).to_string()
+                } else {
+                    format!("{}.{}", path, String::from_utf8_lossy(&key))
+                };
+                let (val, end) = canonical(buf, end, depth + 1, &key_path, policy, fixes)?;
+                pos = end;
+
+                let problem = match prev.as_ref() {
+                    Some(p) if *p == key => Some("duplicate"),
+                    Some(p) if *p > key => Some("out of order"),
+                    _ => None,
+                };
+                if let Some(problem) = problem {
+                    if policy == KeyPolicy::Strict {
+                        return Err(bad!("{} dictionary key {}", problem, key_path));
+                    }
+                    fixes.push(format!("{} dictionary key {}", problem, key_path));
+                }
+
+                if policy == KeyPolicy::KeepFirst {
+                    entries.entry(key.clone()).or_insert(val);
+                } else {
+                    entries.insert(key.clone(), val);
+                }
+                prev = Some(key);
+            }
+
+            let mut out = vec![b'd'];
+            for (k, v) in entries {
+                out.extend(k.len().to_string().as_bytes());
+                out.push(b':');
+                out.extend(k);
+                out.extend(v);
+            }
+            out.push(b'e');
+            Ok((out, pos + 1))
+        }
+        Some(other) => Err(bad!(
+            "unexpected byte {:?} at {}",
+            *other as char,
+            path_or_root(path)
+        )),
+        None => Err(bad!("input ended inside {}", path_or_root(path))),
+    }
+}
+
+// returns the string's bytes and the position just past them
+fn string(buf: &[u8], pos: usize) -> Result<(&[u8], usize)> {
+    let colon = find(buf, pos, b':')?;
+    let len: usize = std::str::from_utf8(&buf[pos..colon])
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| bad!("malformed string length at position {}", pos))?;
+    let start = colon + 1;
+    let end = start
+        .checked_add(len)
+        .filter(|end| *end <= buf.len())
+        .ok_or_else(|| bad!("string of length {} runs past end of input", len))?;
+    Ok((&buf[start..end], end))
+}
+
+fn find(buf: &[u8], pos: usize, needle: u8) -> Result<usize> {
+    buf[pos..]
+        .iter()
+        .position(|b| *b == needle)
+        .map(|off| pos + off)
+        .ok_or_else(|| bad!("missing {:?} after position {}", needle as char, pos))
+}
+
+fn path_or_root(path: &str) -> &str {
+    if path.is_empty() {
+        "<root>"
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn decode(buf: &[u8]) -> Value {
+        serde_bencode::from_bytes(buf).unwrap()
+    }
+
+    #[test]
+    fn canonical_input_round_trips() {
+        for buf in [
+            &b"i0e"[..],
+            b"i-42e",
+            b"0:",
+            b"5:hello",
+            b"le",
+            b"de",
+            b"li1e3:twol4:deepee",
+            b"d1:ai1e1:bl1:xi-1ee4:infod6:lengthi12e4:name4:test12:piece lengthi16384eee",
+        ] {
+            assert_eq!(
+                encode(&decode(buf)),
+                buf,
+                "{}",
+                String::from_utf8_lossy(buf)
+            );
+        }
+    }
+
+    #[test]
+    fn sorts_keys_by_raw_bytes() {
+        let value = Value::Dict(HashMap::from([
+            (b"b".to_vec(), Value::Int(2)),
+            (b"a".to_vec(), Value::Int(1)),
+            (b"B".to_vec(), Value::Int(0)),
+            (b"ab".to_vec(), Value::Int(3)),
+        ]));
+        assert_eq!(encode(&value), b"d1:Bi0e1:ai1e2:abi3e1:bi2ee");
+    }
+
+    #[test]
+    fn string_lengths_count_bytes() {
+        let value = Value::List(vec![
+            Value::Bytes("héllo".as_bytes().to_vec()),
+            Value::Bytes(vec![0, 0xff, b':', b'e']),
+        ]);
+        let buf = encode(&value);
+        assert_eq!(buf, b"l6:h\xc3\xa9llo4:\x00\xff:ee");
+        assert_eq!(encode(&decode(&buf)), buf);
+    }
+
+    #[test]
+    fn agrees_with_normalize() {
+        let messy = b"d1:zi1e1:ad1:yle1:xi0eee";
+        let (normalized, fixes) = normalize(messy, KeyPolicy::KeepLast).unwrap();
+        assert!(!fixes.is_empty());
+        assert_eq!(encode(&decode(messy)), normalized);
+    }
+
+    fn message(err: Error) -> String {
+        match err {
+            Error::Bencode(msg) => msg,
+            other => panic!("expected a bencode error, got {}", other),
+        }
+    }
+
+    #[test]
+    fn sorts_unsorted_keys() {
+        let (out, fixes) = normalize(b"d1:bi2e1:ai1ee", KeyPolicy::KeepLast).unwrap();
+        assert_eq!(out, b"d1:ai1e1:bi2ee");
+        assert_eq!(fixes, ["out of order dictionary key a"]);
+    }
+
+    #[test]
+    fn duplicate_keys_follow_policy() {
+        let buf = b"d1:ai1e1:ai2ee";
+        let (first, fixes) = normalize(buf, KeyPolicy::KeepFirst).unwrap();
+        assert_eq!(first, b"d1:ai1ee");
+        assert_eq!(fixes, ["duplicate dictionary key a"]);
+        let (last, _) = normalize(buf, KeyPolicy::KeepLast).unwrap();
+        assert_eq!(last, b"d1:ai2ee");
+
+        assert_eq!(
+            raw_value(buf, b"a", KeyPolicy::KeepFirst).unwrap(),
+            Some(4..7)
+        );
+        assert_eq!(
+            raw_value(buf, b"a", KeyPolicy::KeepLast).unwrap(),
+            Some(10..13)
+        );
+    }
+
+    #[test]
+    fn strict_names_the_offending_key() {
+        let err = normalize(b"d4:infod1:zi0e1:yi0eee", KeyPolicy::Strict).unwrap_err();
+        assert_eq!(message(err), "out of order dictionary key info.y");
+        let err = normalize(b"d1:ld1:ai0e1:ai0eee", KeyPolicy::Strict).unwrap_err();
+        assert_eq!(message(err), "duplicate dictionary key l.a");
+        let err = normalize(b"d1:lld1:bi0e1:ai0eeee", KeyPolicy::Strict).unwrap_err();
+        assert_eq!(message(err), "out of order dictionary key l[0].a");
+    }
+
+    #[test]
+    fn canonical_input_has_no_fixes() {
+        let buf = b"d1:ai1e1:bl1:xi-1ee4:infod6:lengthi12eee";
+        for policy in [KeyPolicy::Strict, KeyPolicy::KeepFirst, KeyPolicy::KeepLast] {
+            let (out, fixes) = normalize(buf, policy).unwrap();
+            assert_eq!(out, buf);
+            assert!(fixes.is_empty());
+        }
+    }
+
+    #[test]
+    fn refuses_deep_nesting() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(value_len(&nested(MAX_DEPTH)).is_ok());
+        for buf in [nested(MAX_DEPTH + 1), vec![b'l'; 500_000]] {
+            let err = value_len(&buf).unwrap_err();
+            assert!(message(err).starts_with("nested more than 256 levels deep"));
+            assert!(normalize(&buf, KeyPolicy::KeepLast).is_err());
+            assert!(super::decode::<Value>(&buf).is_err());
+        }
+        let deep_dict = [&b"d1:a"[..], &nested(MAX_DEPTH), b"e"].concat();
+        assert!(value_len(&deep_dict).is_err());
+        assert!(raw_value(&deep_dict, b"a", KeyPolicy::KeepLast).is_err());
+    }
+
+    #[test]
+    fn non_canonical_integers() {
+        for (buf, fixed) in [
+            (&b"i03e"[..], &b"i3e"[..]),
+            (b"i-0e", b"i0e"),
+            (b"i-007e", b"i-7e"),
+        ] {
+            let (out, fixes) = normalize(buf, KeyPolicy::KeepLast).unwrap();
+            assert_eq!(out, fixed);
+            assert_eq!(fixes.len(), 1);
+            assert!(normalize(buf, KeyPolicy::Strict).is_err());
+        }
+        let err = normalize(b"d1:ai00ee", KeyPolicy::Strict).unwrap_err();
+        assert_eq!(message(err), "non-canonical integer \"00\" at a");
+    }
+
+    #[test]
+    fn rejects_malformed_integers() {
+        assert!(normalize(b"i9223372036854775807e", KeyPolicy::Strict).is_ok());
+        assert!(normalize(b"i-9223372036854775808e", KeyPolicy::Strict).is_ok());
+        for buf in [
+            &b"i9223372036854775808e"[..],
+            b"i-9223372036854775809e",
+            b"ie",
+            b"i-e",
+            b"i+1e",
+            b"i1.5e",
+            b"i12",
+        ] {
+            for policy in [KeyPolicy::Strict, KeyPolicy::KeepLast] {
+                assert!(
+                    normalize(buf, policy).is_err(),
+                    "{}",
+                    String::from_utf8_lossy(buf)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,1698 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::{self, SeekFrom},
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{mpsc, watch},
+    task::JoinSet,
+    time::{timeout, Instant as TokioInstant},
+};
+
+use crate::{
+    error::{Detailed, Error},
+    magnet::MagnetLink,
+    metainfo::{FileSpan, InfoDict, Metainfo},
+    peer, tracker,
+};
+
+pub mod picker;
+pub mod resume;
+
+pub const MAX_PEERS: usize = 5;
+// a peer that sends this many pieces with bad hashes is dropped, and a disk
+// that this many pieces don't read back intact from stops the download
+const MAX_BAD_PIECES: u32 = 3;
+const IDLE_POLL: Duration = Duration::from_millis(250);
+// don't let a tracker that answers with a tiny interval make us hammer it
+const MIN_REANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+const FINAL_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+// a peer gets this long to connect and handshake, then to unchoke us once we
+// say we're interested, before its slot goes to someone else
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(30);
+// pieces longer than this are fetched in parts of this size, so that several
+// peers can work on one at once rather than one peer fetching it all serially
+const PART_SZ: u32 = 1024 * 1024;
+// the unit an endgame threshold in blocks counts in, as peers are asked for them
+const BLOCK_SZ: u64 = 16 * 1024;
+// how much a new throughput measurement moves a peer's average
+const RATE_WEIGHT: f64 = 0.3;
+// a peer is slow next to the best one if it delivers under this fraction of
+// its throughput, or takes over this many times its round trip to do it.
+// slow peers are kept to pieces the fast ones can't get.
+const SLOW_RATE_FRACTION: f64 = 0.5;
+const SLOW_RTT_FACTOR: u32 = 3;
+// BEP 3 lets a peer with no pieces skip the bitfield, so no bitfield by then
+// means an empty one
+const BITFIELD_WAIT: Duration = Duration::from_secs(5);
+// transient disk errors are retried this many times, doubling the wait each time
+const DISK_RETRIES: u32 = 5;
+const DISK_RETRY_BASE: Duration = Duration::from_millis(100);
+// while the disk is full, how often to check whether space has been freed
+const DISK_FULL_POLL: Duration = Duration::from_secs(5);
+// the same errno on Linux, macOS and the BSDs
+const ENOSPC: i32 = 28;
+
+// pieces nobody is working on, plus the ones currently being fetched so idle
+// workers can tell "wait for a retry" apart from "nothing left to do"
+struct WorkQueue {
+    pending: VecDeque<u32>,
+    // piece => how many peers are fetching it. once nothing is pending, idle
+    // peers double up on these so one slow peer can't hold up the end
+    in_flight: HashMap<u32, usize>,
+    // the in-flight pieces too big to fetch from one peer, as they come together
+    parts: HashMap<u32, PartialPiece>,
+    // every piece a peer has delivered, so the others fetching it can stop
+    done: watch::Sender<HashSet<u32>>,
+    stats: DownloadStats,
+    picker: Box<dyn picker::PiecePicker>,
+    endgame: Endgame,
+    peers: HashMap<SocketAddr, PeerStats>,
+}
+
+// what has been measured of a peer while fetching from it
+#[derive(Default)]
+struct PeerStats {
+    // bytes per second it has been delivering, on average
+    rate: Option<f64>,
+    block_rtt: Option<Duration>,
+    bitfield: Vec<u8>,
+}
+
+impl PeerStats {
+    fn has_piece(&self, index: u32) -> bool {
+        self.bitfield
+            .get(index as usize / 8)
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+}
+
+// when idle peers start doubling up on pieces others are already fetching
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EndgameTrigger {
+    // whenever a peer has nothing new it could fetch
+    Immediately,
+    // once at most this many blocks are still to come
+    Blocks(u64),
+    // once at most this percentage of the torrent is still to come
+    Percent(f64),
+}
+
+impl FromStr for EndgameTrigger {
+    type Err = anyhow::Error;
+
+    // "now", a number of blocks, or a percentage like "2.5%"
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s == "now" {
+            return Ok(EndgameTrigger::Immediately);
+        }
+        match s.strip_suffix('%') {
+            Some(pct) => match pct.parse::<f64>()? {
+                pct if (0.0..=100.0).contains(&pct) => Ok(EndgameTrigger::Percent(pct)),
+                pct => Err(anyhow!("{}% is not a percentage", pct)),
+            },
+            None => Ok(EndgameTrigger::Blocks(s.parse()?)),
+        }
+    }
+}
+
+// which peers double up once endgame has begun
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EndgameStrategy {
+    AllPeers,
+    // only this many of the peers delivering fastest; the slow ones would
+    // mostly be fetching bytes that arrive from someone else first
+    Fastest(usize),
+}
+
+impl FromStr for EndgameStrategy {
+    type Err = anyhow::Error;
+
+    // "all", or how many of the fastest peers
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "all" => Ok(EndgameStrategy::AllPeers),
+            n => match n.parse()? {
+                0 => Err(anyhow!("endgame needs at least one peer")),
+                n => Ok(EndgameStrategy::Fastest(n)),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Endgame {
+    pub trigger: EndgameTrigger,
+    pub strategy: EndgameStrategy,
+}
+
+impl Default for Endgame {
+    fn default() -> Self {
+        Endgame {
+            trigger: EndgameTrigger::Immediately,
+            strategy: EndgameStrategy::AllPeers,
+        }
+    }
+}
+
+// bytes received that didn't end up in the output
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DownloadStats {
+    // pieces that failed hash verification
+    pub corrupt_bytes: u64,
+    // endgame duplicates: pieces another peer delivered first
+    pub redundant_bytes: u64,
+    // time spent reading pieces back from disk to re-check them
+    pub verify_time: Duration,
+}
+
+impl DownloadStats {
+    // `progress` with the bytes we received but didn't keep added in
+    fn report(&self, progress: tracker::Progress) -> tracker::Progress {
+        tracker::Progress {
+            downloaded: progress.downloaded + self.corrupt_bytes + self.redundant_bytes,
+            corrupt: self.corrupt_bytes,
+            redundant: self.redundant_bytes,
+            ..progress
+        }
+    }
+}
+
+// a piece being put together from parts fetched by different peers
+struct PartialPiece {
+    buf: Vec<u8>,
+    // offsets of the parts no peer has been asked for yet
+    unassigned: VecDeque<u32>,
+    // part offset => how many peers are fetching it
+    fetching: HashMap<u32, usize>,
+    // offsets of the parts that haven't arrived
+    missing: HashSet<u32>,
+}
+
+enum Next {
+    Piece(u32),
+    // piece, offset and length of one part of a big piece
+    Part(u32, u32, u32),
+    Wait,
+    Finished,
+}
+
+impl WorkQueue {
+    fn next_for(&mut self, peer: &peer::PeerState, info: &InfoDict) -> Next {
+        self.picker.update_peer(peer.remote(), peer.bitfield());
+        let stats = self.peers.entry(peer.remote()).or_default();
+        if stats.bitfield != peer.bitfield() {
+            stats.bitfield = peer.bitfield().to_vec();
+        }
+        // finish big pieces already started before starting more of them
+        if let Some(part) = self.next_part(peer, false) {
+            return part;
+        }
+        let candidates: Vec<u32> = self
+            .pending
+            .iter()
+            .copied()
+            .filter(|i| peer.has_piece(*i))
+            .collect();
+        if candidates.is_empty() {
+            if self.in_flight.is_empty() {
+                return Next::Finished;
+            }
+            if !self.in_endgame(info) || !self.may_double_up(peer.remote()) {
+                return Next::Wait;
+            }
+            // endgame: join in on whichever piece or part the fewest peers
+            // are fetching
+            if let Some(part) = self.next_part(peer, true) {
+                return part;
+            }
+            let doubled = self
+                .in_flight
+                .iter_mut()
+                .filter(|(i, _)| peer.has_piece(**i) && !self.parts.contains_key(i))
+                .min_by_key(|(_, n)| **n);
+            return match doubled {
+                Some((index, n)) => {
+                    *n += 1;
+                    Next::Piece(*index)
+                }
+                None => Next::Wait,
+            };
+        }
+        let candidates = if self.is_slow(peer.remote()) {
+            self.least_contended(candidates)
+        } else {
+            candidates
+        };
+        let index = self.picker.pick(&candidates);
+        self.pending.retain(|i| *i != index);
+        self.in_flight.insert(index, 1);
+        let size = info.piece_size(index);
+        if size <= PART_SZ {
+            return Next::Piece(index);
+        }
+        let offsets: Vec<u32> = (0..size).step_by(PART_SZ as usize).collect();
+        self.parts.insert(
+            index,
+            PartialPiece {
+                buf: vec![0; size as usize],
+                unassigned: offsets.iter().copied().collect(),
+                fetching: HashMap::new(),
+                missing: offsets.into_iter().collect(),
+            },
+        );
+        self.next_part(peer, false).unwrap_or(Next::Wait)
+    }
+
+    // a part of a started piece the peer has: one nobody has been asked for,
+    // or with `double_up`, the missing one the fewest peers are fetching
+    fn next_part(&mut self, peer: &peer::PeerState, double_up: bool) -> Option<Next> {
+        for (&index, partial) in self.parts.iter_mut() {
+            if !peer.has_piece(index) {
+                continue;
+            }
+            let begin = if double_up {
+                let fetching = &partial.fetching;
+                partial
+                    .missing
+                    .iter()
+                    .copied()
+                    .min_by_key(|begin| fetching.get(begin).copied().unwrap_or(0))
+            } else {
+                partial.unassigned.pop_front()
+            };
+            if let Some(begin) = begin {
+                *partial.fetching.entry(begin).or_insert(0) += 1;
+                let length = PART_SZ.min(partial.buf.len() as u32 - begin);
+                return Some(Next::Part(index, begin, length));
+            }
+        }
+        None
+    }
+
+    fn requeue_part(&mut self, index: u32, begin: u32) {
+        let Some(partial) = self.parts.get_mut(&index) else {
+            return;
+        };
+        match partial.fetching.get_mut(&begin) {
+            Some(n) if *n > 1 => *n -= 1,
+            Some(_) => {
+                partial.fetching.remove(&begin);
+                partial.unassigned.push_back(begin);
+            }
+            // another peer delivered it in the meantime
+            None => {}
+        }
+    }
+
+    // keeps a part that arrived, returning the whole piece once it's the last
+    fn part_done(&mut self, index: u32, begin: u32, data: &[u8]) -> Option<Vec<u8>> {
+        let first = self
+            .parts
+            .get_mut(&index)
+            .is_some_and(|partial| partial.missing.remove(&begin));
+        if !first {
+            self.stats.redundant_bytes += data.len() as u64;
+            return None;
+        }
+        let partial = self.parts.get_mut(&index)?;
+        partial.fetching.remove(&begin);
+        partial.buf[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+        if !partial.missing.is_empty() {
+            return None;
+        }
+        self.parts.remove(&index).map(|partial| partial.buf)
+    }
+
+    fn in_endgame(&self, info: &InfoDict) -> bool {
+        let left = || self.pending.iter().chain(self.in_flight.keys());
+        match self.endgame.trigger {
+            EndgameTrigger::Immediately => true,
+            EndgameTrigger::Blocks(max) => {
+                let blocks = left()
+                    .map(|i| (info.piece_size(*i) as u64).div_ceil(BLOCK_SZ))
+                    .sum::<u64>();
+                blocks <= max
+            }
+            EndgameTrigger::Percent(max) => {
+                let bytes = left().map(|i| info.piece_size(*i) as u64).sum::<u64>();
+                bytes as f64 * 100.0 <= max * info.length() as f64
+            }
+        }
+    }
+
+    fn may_double_up(&self, peer: SocketAddr) -> bool {
+        match self.endgame.strategy {
+            EndgameStrategy::AllPeers => true,
+            EndgameStrategy::Fastest(n) => {
+                // a peer nothing has been measured for yet ranks last
+                let rate = |addr| {
+                    self.peers
+                        .get(addr)
+                        .and_then(|p: &PeerStats| p.rate)
+                        .unwrap_or(0.0)
+                };
+                let ours = rate(&peer);
+                self.peers.keys().filter(|p| rate(p) > ours).count() < n
+            }
+        }
+    }
+
+    // a peer nothing has been measured for yet gets the benefit of the doubt
+    fn is_slow(&self, peer: SocketAddr) -> bool {
+        let Some(stats) = self.peers.get(&peer) else {
+            return false;
+        };
+        let best_rate = self
+            .peers
+            .values()
+            .filter_map(|p| p.rate)
+            .fold(0.0, f64::max);
+        let best_rtt = self.peers.values().filter_map(|p| p.block_rtt).min();
+        let slow_rate = stats
+            .rate
+            .is_some_and(|rate| rate < best_rate * SLOW_RATE_FRACTION);
+        let slow_rtt = match (stats.block_rtt, best_rtt) {
+            (Some(rtt), Some(best)) => rtt > best * SLOW_RTT_FACTOR,
+            _ => false,
+        };
+        slow_rate || slow_rtt
+    }
+
+    // the candidates the fewest fast peers have, so a slow peer works on
+    // pieces nobody quicker could fetch instead of holding up ones they could
+    fn least_contended(&self, candidates: Vec<u32>) -> Vec<u32> {
+        let fast: Vec<&PeerStats> = self
+            .peers
+            .iter()
+            .filter(|(addr, _)| !self.is_slow(**addr))
+            .map(|(_, stats)| stats)
+            .collect();
+        let contention = |index: &u32| fast.iter().filter(|p| p.has_piece(*index)).count();
+        let Some(least) = candidates.iter().map(contention).min() else {
+            return candidates;
+        };
+        candidates
+            .into_iter()
+            .filter(|index| contention(index) == least)
+            .collect()
+    }
+
+    fn record_delivery(
+        &mut self,
+        peer: SocketAddr,
+        bytes: usize,
+        elapsed: Duration,
+        block_rtt: Option<Duration>,
+    ) {
+        let rate = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        let stats = self.peers.entry(peer).or_default();
+        stats.rate = Some(match stats.rate {
+            Some(avg) => avg + RATE_WEIGHT * (rate - avg),
+            None => rate,
+        });
+        stats.block_rtt = block_rtt;
+    }
+
+    fn requeue(&mut self, index: u32) {
+        match self.in_flight.get_mut(&index) {
+            Some(n) if *n > 1 => *n -= 1,
+            Some(_) => {
+                self.in_flight.remove(&index);
+                self.pending.push_back(index);
+            }
+            // another peer delivered it in the meantime
+            None => {}
+        }
+    }
+
+    // fetches a delivered piece all over again, as when what was written of
+    // it to disk didn't read back intact
+    fn redo(&mut self, index: u32, bytes: usize) {
+        self.done.send_modify(|done| {
+            done.remove(&index);
+        });
+        self.pending.push_back(index);
+        self.stats.corrupt_bytes += bytes as u64;
+    }
+
+    // false if another peer already delivered the piece
+    fn complete(&mut self, index: u32) -> bool {
+        if self.in_flight.remove(&index).is_none() {
+            return false;
+        }
+        self.done.send_modify(|done| {
+            done.insert(index);
+        });
+        self.picker.piece_done(index);
+        true
+    }
+}
+
+type SharedQueue = Arc<Mutex<WorkQueue>>;
+
+// the files a torrent's data is written to, laid end to end in torrent order
+pub struct Storage {
+    // None for a file `open` found missing; its data can't be read
+    files: Vec<(FileSpan, Option<File>)>,
+    // what `open` found wrong with the files on disk
+    problems: Vec<String>,
+}
+
+impl Storage {
+    // a single-file torrent is written to `target` itself; a multi-file
+    // torrent treats `target` as a directory and puts every file at its
+    // relative path beneath it. every file is created at its final size.
+    pub async fn create(info: &InfoDict, target: &Path) -> anyhow::Result<Self> {
+        Self::prepare(info, target, true).await
+    }
+
+    // like `create`, but keeps whatever an earlier run already wrote
+    pub async fn reopen(info: &InfoDict, target: &Path) -> anyhow::Result<Self> {
+        Self::prepare(info, target, false).await
+    }
+
+    async fn prepare(info: &InfoDict, target: &Path, truncate: bool) -> anyhow::Result<Self> {
+        let spans = info.file_spans();
+        let multi_file = matches!(info, InfoDict::MultiFile { .. });
+        let mut files = Vec::with_capacity(spans.len());
+        for span in spans {
+            let path = if multi_file {
+                target.join(relative_path(&span.path)?)
+            } else {
+                target.to_path_buf()
+            };
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .with_context(|| format!("error creating directory {}", parent.display()))?;
+            }
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(truncate)
+                .open(&path)
+                .await
+                .with_context(|| format!("error opening {}", path.display()))?;
+            f.set_len(span.length)
+                .await
+                .with_context(|| format!("error sizing {}", path.display()))?;
+            files.push((span, Some(f)));
+        }
+        Ok(Storage {
+            files,
+            problems: vec![],
+        })
+    }
+
+    // opens the files a download left at `target`, laid out as `create`
+    // would, without changing them. files may be missing or shorter than the
+    // torrent says, and reads of what isn't there fail; `problems` says which.
+    pub async fn open(info: &InfoDict, target: &Path) -> anyhow::Result<Self> {
+        let spans = info.file_spans();
+        let multi_file = matches!(info, InfoDict::MultiFile { .. });
+        let mut files = Vec::with_capacity(spans.len());
+        let mut problems = vec![];
+        for span in spans {
+            let path = if multi_file {
+                target.join(relative_path(&span.path)?)
+            } else {
+                target.to_path_buf()
+            };
+            let f = match File::open(&path).await {
+                Ok(f) => f,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    problems.push(format!("{} is missing", path.display()));
+                    files.push((span, None));
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("error opening {}", path.display()))
+                }
+            };
+            let len = f
+                .metadata()
+                .await
+                .with_context(|| format!("error reading {}", path.display()))?
+                .len();
+            if len != span.length {
+                problems.push(format!(
+                    "{} is {} bytes, the torrent says {}",
+                    path.display(),
+                    len,
+                    span.length
+                ));
+            }
+            files.push((span, Some(f)));
+        }
+        Ok(Storage { files, problems })
+    }
+
+    // missing files and files of the wrong size, as found by `open`
+    pub fn problems(&self) -> &[String] {
+        &self.problems
+    }
+
+    // writes `data` starting at `offset` into the torrent's data, splitting it
+    // across every file it overlaps
+    pub async fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset + data.len() as u64;
+        for (span, f) in self.files.iter_mut() {
+            let span_start = span.offset;
+            let span_end = span_start + span.length;
+            if span_end <= offset || span_start >= end {
+                continue;
+            }
+            let from = offset.max(span_start);
+            let to = end.min(span_end);
+            let f = f.as_mut().ok_or_else(missing_file)?;
+            f.seek(SeekFrom::Start(from - span_start)).await?;
+            f.write_all(&data[(from - offset) as usize..(to - offset) as usize])
+                .await?;
+        }
+        Ok(())
+    }
+
+    // makes sure the `len` bytes of the torrent's data starting at `offset`
+    // have reached the disk, not just the page cache
+    pub async fn sync_at(&mut self, offset: u64, len: usize) -> io::Result<()> {
+        let end = offset + len as u64;
+        for (span, f) in self.files.iter_mut() {
+            if span.offset + span.length <= offset || span.offset >= end {
+                continue;
+            }
+            let f = f.as_mut().ok_or_else(missing_file)?;
+            f.flush().await?;
+            f.sync_data().await?;
+        }
+        Ok(())
+    }
+
+    // reads `len` bytes of the torrent's data starting at `offset` back from
+    // disk, after flushing anything still buffered
+    pub async fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let end = offset + len as u64;
+        let mut buf = vec![0; len];
+        for (span, f) in self.files.iter_mut() {
+            let span_start = span.offset;
+            let span_end = span_start + span.length;
+            if span_end <= offset || span_start >= end {
+                continue;
+            }
+            let from = offset.max(span_start);
+            let to = end.min(span_end);
+            let f = f.as_mut().ok_or_else(missing_file)?;
+            f.flush().await?;
+            f.seek(SeekFrom::Start(from - span_start)).await?;
+            f.read_exact(&mut buf[(from - offset) as usize..(to - offset) as usize])
+                .await?;
+        }
+        Ok(buf)
+    }
+}
+
+fn missing_file() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "file is missing")
+}
+
+// hashes every piece in `storage`, returning which ones match the torrent. a
+// piece that runs past the end of a short file, or into a missing one, doesn't.
+pub async fn check_pieces(info: &InfoDict, storage: &mut Storage) -> anyhow::Result<Vec<bool>> {
+    let mut good = Vec::with_capacity(info.num_pieces());
+    for index in 0..info.num_pieces() as u32 {
+        let offset = index as u64 * info.piece_length() as u64;
+        let piece = match storage
+            .read_at(offset, info.piece_size(index) as usize)
+            .await
+        {
+            Ok(piece) => piece,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::UnexpectedEof | io::ErrorKind::NotFound
+                ) =>
+            {
+                good.push(false);
+                continue;
+            }
+            Err(e) => {
+                return Err(Error::Io(e)).with_context(|| format!("error reading piece {}", index))
+            }
+        };
+        good.push(info.verify_piece(index, &piece));
+    }
+    Ok(good)
+}
+
+#[derive(Debug, PartialEq)]
+enum DiskFailure {
+    // worth retrying right away, e.g. EINTR or EAGAIN
+    Transient,
+    // will succeed once the user frees some space
+    Full,
+    // read-only filesystem, permissions, bad path, ...: give up
+    Fatal,
+}
+
+fn classify(e: &io::Error) -> DiskFailure {
+    match e.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            DiskFailure::Transient
+        }
+        _ if e.raw_os_error() == Some(ENOSPC) => DiskFailure::Full,
+        _ => DiskFailure::Fatal,
+    }
+}
+
+// writes a piece, retrying transient errors with backoff and waiting out a
+// full disk; only fatal errors are returned
+async fn write_piece(out: &mut Storage, offset: u64, piece: &[u8]) -> io::Result<()> {
+    let mut retries = 0;
+    let mut paused = false;
+    loop {
+        let e = match out.write_at(offset, piece).await {
+            Ok(()) => {
+                if paused {
+                    eprintln!("disk has space again, resuming");
+                }
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        match classify(&e) {
+            DiskFailure::Transient if retries < DISK_RETRIES => {
+                eprintln!("transient disk error, retrying: {}", e);
+                tokio::time::sleep(DISK_RETRY_BASE * 2u32.pow(retries)).await;
+                retries += 1;
+            }
+            DiskFailure::Full => {
+                if !paused {
+                    eprintln!("download paused: {}; waiting for space to be freed", e);
+                    paused = true;
+                }
+                tokio::time::sleep(DISK_FULL_POLL).await;
+            }
+            _ => return Err(e),
+        }
+    }
+}
+
+// turns a metainfo path list into a relative path, refusing anything that
+// could land outside the download directory
+fn relative_path(components: &[String]) -> crate::error::Result<PathBuf> {
+    let path: PathBuf = components.iter().collect();
+    let escapes = components.is_empty()
+        || components
+            .iter()
+            .any(|c| c.is_empty() || c.contains(['/', '\\']))
+        || path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)));
+    if escapes {
+        return Err(Error::Metainfo(format!(
+            "unsafe file path {:?}",
+            components.join("/")
+        )));
+    }
+    Ok(path)
+}
+
+// re-announces to a torrent's trackers on the schedule the last one asked for
+pub struct Reannouncer {
+    tiers: tracker::TrackerTiers,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    bind_addr: Option<IpAddr>,
+    last: TokioInstant,
+    interval: Duration,
+    min_interval: Duration,
+}
+
+impl Reannouncer {
+    // announces `progress` (normally a "started" event) to the first tier
+    // that answers, returning the peers it gave out. `tracker_ids` are ids
+    // trackers handed out in an earlier session.
+    pub async fn start(
+        metainf: &Metainfo,
+        peer_id: [u8; 20],
+        bind_addr: Option<IpAddr>,
+        progress: tracker::Progress,
+        tracker_ids: BTreeMap<String, Vec<u8>>,
+    ) -> anyhow::Result<(Vec<SocketAddr>, Self)> {
+        let mut tiers = tracker::TrackerTiers::new(&metainf.announce, &metainf.announce_list);
+        tiers.set_tracker_ids(tracker_ids);
+        let info_hash = metainf.info_hash()?;
+        let (_, announced) = tiers
+            .announce(progress, info_hash, peer_id, bind_addr)
+            .await?;
+        let mut reannouncer = Reannouncer {
+            tiers,
+            info_hash,
+            peer_id,
+            bind_addr,
+            last: TokioInstant::now(),
+            interval: Duration::ZERO,
+            min_interval: Duration::ZERO,
+        };
+        reannouncer.update(&announced);
+        Ok((announced.peers, reannouncer))
+    }
+
+    pub fn tracker_ids(&self) -> &BTreeMap<String, Vec<u8>> {
+        self.tiers.tracker_ids()
+    }
+
+    // when the tracker expects to hear from us again
+    pub fn due(&self) -> TokioInstant {
+        self.last + self.interval
+    }
+
+    // the soonest the tracker is willing to hear from us again
+    fn earliest(&self) -> TokioInstant {
+        self.last + self.min_interval
+    }
+
+    fn update(&mut self, announced: &tracker::Announce) {
+        self.last = TokioInstant::now();
+        self.interval = announced.interval.max(MIN_REANNOUNCE_INTERVAL);
+        self.min_interval = announced.min_interval.unwrap_or(Duration::ZERO);
+    }
+
+    pub async fn announce(
+        &mut self,
+        progress: tracker::Progress,
+    ) -> anyhow::Result<Vec<SocketAddr>> {
+        let (_, announced) = self
+            .tiers
+            .announce(progress, self.info_hash, self.peer_id, self.bind_addr)
+            .await?;
+        self.update(&announced);
+        Ok(announced.peers)
+    }
+}
+
+// asks the torrent's trackers for peers, tier by tier, returning them along
+// with what's needed to keep announcing during the download. `resume` is
+// what an interrupted download of the torrent left, if any.
+pub async fn announce_peers(
+    metainf: &Metainfo,
+    peer_id: [u8; 20],
+    bind_addr: Option<IpAddr>,
+    resume: Option<&resume::ResumeState>,
+) -> anyhow::Result<(Vec<SocketAddr>, Reannouncer)> {
+    let started = tracker::Progress {
+        event: Some(tracker::AnnounceEvent::Started),
+        ..resumed_progress(metainf, resume)
+    };
+    let tracker_ids = resume.map(|r| r.tracker_ids()).unwrap_or_default();
+    let (peers, reannouncer) =
+        Reannouncer::start(metainf, peer_id, bind_addr, started, tracker_ids).await?;
+    if peers.is_empty() {
+        anyhow::bail!("tracker returned no peers");
+    }
+    Ok((peers, reannouncer))
+}
+
+// what to report for a download that may be picking up where `resume` left off
+fn resumed_progress(metainf: &Metainfo, resume: Option<&resume::ResumeState>) -> tracker::Progress {
+    let mut progress = tracker::Progress::starting(metainf.info.length());
+    let Some(resume) = resume else {
+        return progress;
+    };
+    progress.downloaded = resume.downloaded;
+    progress.uploaded = resume.uploaded;
+    for index in 0..metainf.info.num_pieces() as u32 {
+        if resume.has_piece(index) {
+            progress.left -= metainf.info.piece_size(index) as u64;
+        }
+    }
+    progress
+}
+
+// peers for a magnet link: any given directly in it, then those from the first
+// of its trackers that answers
+pub async fn magnet_peers(
+    link: &MagnetLink,
+    peer_id: [u8; 20],
+    bind_addr: Option<IpAddr>,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let mut peers = link.peers.clone();
+    for tracker_addr in link.trackers.iter() {
+        eprintln!("fetching peers from tracker at {}", tracker_addr);
+        match tracker::announce(
+            tracker_addr,
+            tracker::Progress::before_metadata(),
+            link.info_hash,
+            peer_id,
+            bind_addr,
+            None,
+        )
+        .await
+        {
+            Ok(announced) if !announced.peers.is_empty() => {
+                peers.extend(announced.peers);
+                break;
+            }
+            Ok(_) => eprintln!("tracker {} returned no peers", tracker_addr),
+            Err(e) => eprintln!("tracker {} failed: {:#}", tracker_addr, e),
+        }
+    }
+    if peers.is_empty() {
+        anyhow::bail!("magnet link's trackers returned no peers");
+    }
+    Ok(peers)
+}
+
+// connects to `peers` in turn until one hands over the magnet link's info
+// dict, returning the reconstructed metainfo and that peer
+pub async fn magnet_metainfo(
+    link: &MagnetLink,
+    peers: &[SocketAddr],
+    peer_id: [u8; 20],
+    dialer: peer::Dialer,
+) -> anyhow::Result<(Metainfo, peer::PeerState)> {
+    for &addr in peers {
+        let fetched = async {
+            let mut peer =
+                peer::PeerState::connect_by_hash(addr, link.info_hash, peer_id, dialer).await?;
+            peer.wait_for_handshake().await?;
+            peer.exchange_extension_handshakes().await?;
+            let (info, info_bytes) = peer.fetch_metadata().await?;
+            anyhow::Ok((link.to_metainfo(info, info_bytes), peer))
+        }
+        .await;
+        match fetched {
+            Ok(found) => {
+                // the metadata matched the info hash, so every other peer would
+                // hand over the same broken dict: no point asking them
+                found
+                    .0
+                    .validate()
+                    .context("the torrent's metadata is invalid")?;
+                if let Err(e) = found.0.cache().await {
+                    eprintln!("warning: couldn't cache metadata: {:#}", e);
+                }
+                return Ok(found);
+            }
+            Err(e) => eprintln!("couldn't get metadata from {}: {:#}", addr, e),
+        }
+    }
+    anyhow::bail!("no peer handed over the torrent's metadata")
+}
+
+// announce, connect to the first peer returned, and wait until it's ready.
+// these one-shot fetches never announce Stopped, so they don't announce
+// Started either: a plain announce just asks for peers
+pub async fn unchoked_peer(
+    metainf: &Metainfo,
+    peer_id: [u8; 20],
+    bind_addr: Option<IpAddr>,
+    dialer: peer::Dialer,
+) -> anyhow::Result<peer::PeerState> {
+    let progress = tracker::Progress::starting(metainf.info.length());
+    let (peers, _) =
+        Reannouncer::start(metainf, peer_id, bind_addr, progress, BTreeMap::new()).await?;
+    let Some(&addr) = peers.first() else {
+        anyhow::bail!("tracker returned no peers");
+    };
+    ready_peer(addr, metainf, peer_id, dialer).await
+}
+
+// fetches the pieces covering the half-open byte range `start..end` of the
+// torrent's data, verifies them, and writes exactly those bytes to `outfile`.
+// returns the range of pieces that were downloaded.
+pub async fn download_range_to(
+    peer: &mut peer::PeerState,
+    metainf: &Metainfo,
+    start: u64,
+    end: u64,
+    outfile: &str,
+) -> anyhow::Result<RangeInclusive<u32>> {
+    let piece_length = u64::from(metainf.info.piece_length());
+    let pieces = (start / piece_length) as u32..=((end - 1) / piece_length) as u32;
+
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(outfile)
+        .await
+        .context("error opening file for writing range")?;
+    for piece_idx in pieces.clone() {
+        eprintln!("fetching piece {}", piece_idx);
+        let piece_buf = peer.get_piece(piece_idx).await?;
+        if !metainf.info.verify_piece(piece_idx, &piece_buf) {
+            anyhow::bail!("piece {} failed hash verification", piece_idx);
+        }
+
+        let piece_start = u64::from(piece_idx) * piece_length;
+        let from = start.max(piece_start) - piece_start;
+        let to = end.min(piece_start + piece_buf.len() as u64) - piece_start;
+        f.write_all(&piece_buf[from as usize..to as usize])
+            .await
+            .context("error writing out range to file")?;
+    }
+    Ok(pieces)
+}
+
+// connects to `addr` and waits until it has sent its bitfield and unchoked us
+pub async fn ready_peer(
+    addr: SocketAddr,
+    metainf: &Metainfo,
+    peer_id: [u8; 20],
+    dialer: peer::Dialer,
+) -> anyhow::Result<peer::PeerState> {
+    let mut peer = timeout(CONNECT_TIMEOUT, async {
+        let mut peer = peer::PeerState::connect(addr, metainf, peer_id, dialer).await?;
+        eprintln!("waiting for handshake");
+        peer.wait_for_handshake().await?;
+        anyhow::Ok(peer)
+    })
+    .await
+    .map_err(|_| anyhow!("no handshake within {:?}", CONNECT_TIMEOUT))??;
+    wait_until_ready(&mut peer).await?;
+    Ok(peer)
+}
+
+// waits for an already handshaken peer's bitfield, then declares interest and
+// waits to be unchoked
+pub async fn wait_until_ready(peer: &mut peer::PeerState) -> anyhow::Result<()> {
+    eprintln!("checking if i have peer's bitfield");
+    let bitfield = timeout(BITFIELD_WAIT, async {
+        while peer.bitfield().is_empty() {
+            let msgs = peer.poll().await?;
+            if msgs.is_empty() {
+                eprintln!("got nothing from peer this round");
+            } else {
+                for m in msgs {
+                    eprintln!("waiting for bitfield, got: {:?}", m);
+                }
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await;
+    match bitfield {
+        Ok(r) => r?,
+        Err(_) => eprintln!("no bitfield from peer, taking it to have no pieces"),
+    }
+
+    eprintln!("indicating interest");
+    peer.indicate_interest().await?;
+
+    eprintln!("checking if peer is choking");
+    timeout(UNCHOKE_TIMEOUT, async {
+        while peer.choking() {
+            let msgs = peer.poll().await?;
+            for m in msgs {
+                eprintln!("waiting for unchoke, got: {:?}", m);
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await
+    .map_err(|_| anyhow!("peer did not unchoke us within {:?}", UNCHOKE_TIMEOUT))??;
+
+    Ok(())
+}
+
+// fetches every piece from up to MAX_PEERS peers at once, handing out work
+// from a shared queue and writing each verified piece at its offset in `out`.
+// peers beyond the first MAX_PEERS stand by to replace ones that drop out.
+// with `verify_writes`, each piece is read back and re-hashed after writing to
+// catch corruption between memory and disk. with a `reannouncer`, the
+// trackers are told about our progress on their schedule and any new peers
+// they return join the download, and they hear "completed" at the end and
+// "stopped" when we quit, including on Ctrl-C. `picker` decides which piece
+// each peer is asked for next, and `pipeline_depth` how many block requests
+// are kept outstanding with each. pieces already marked in `resume` are
+// skipped, and each new one is recorded there as it's written.
+#[allow(clippy::too_many_arguments)]
+pub async fn download_all(
+    metainf: &Metainfo,
+    peers: &[SocketAddr],
+    peer_id: [u8; 20],
+    dialer: peer::Dialer,
+    out: &mut Storage,
+    verify_writes: bool,
+    mut reannouncer: Option<Reannouncer>,
+    picker: Box<dyn picker::PiecePicker>,
+    endgame: Endgame,
+    pipeline_depth: usize,
+    resume: &mut resume::ResumeState,
+) -> anyhow::Result<DownloadStats> {
+    let mut progress = resumed_progress(metainf, Some(resume));
+    // BEP 3: "completed" is only for a download that finishes while running
+    let already_complete = progress.left == 0;
+    let result = tokio::select! {
+        r = fetch_all(
+            metainf,
+            peers,
+            peer_id,
+            dialer,
+            out,
+            verify_writes,
+            &mut reannouncer,
+            &mut progress,
+            picker,
+            endgame,
+            pipeline_depth,
+            resume,
+        ) => r,
+        _ = tokio::signal::ctrl_c() => Err(Error::Interrupted.into()),
+    };
+
+    if let Some(r) = reannouncer.as_mut() {
+        let mut finals = vec![tracker::AnnounceEvent::Stopped];
+        if let Ok(stats) = &result {
+            progress = stats.report(progress);
+            if !already_complete {
+                finals.insert(0, tracker::AnnounceEvent::Completed);
+            }
+        }
+        for event in finals {
+            let report = tracker::Progress {
+                event: Some(event),
+                ..progress
+            };
+            // a tracker that doesn't answer shouldn't keep us from exiting
+            match timeout(FINAL_ANNOUNCE_TIMEOUT, r.announce(report)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => eprintln!("announcing {:?} failed: {:#}", event, e),
+                Err(_) => eprintln!("announcing {:?} timed out", event),
+            }
+        }
+    }
+    if result.is_ok() {
+        if let Err(e) = resume.remove().await {
+            eprintln!("warning: {:#}", e);
+        }
+    }
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn fetch_all(
+    metainf: &Metainfo,
+    peers: &[SocketAddr],
+    peer_id: [u8; 20],
+    dialer: peer::Dialer,
+    out: &mut Storage,
+    verify_writes: bool,
+    reannouncer: &mut Option<Reannouncer>,
+    progress: &mut tracker::Progress,
+    picker: Box<dyn picker::PiecePicker>,
+    endgame: Endgame,
+    pipeline_depth: usize,
+    resume: &mut resume::ResumeState,
+) -> anyhow::Result<DownloadStats> {
+    let num_pieces = metainf.info.num_pieces();
+    let pending: VecDeque<u32> = (0..num_pieces as u32)
+        .filter(|i| !resume.has_piece(*i))
+        .collect();
+    let mut remaining = pending.len();
+    if remaining == 0 {
+        return Ok(DownloadStats::default());
+    }
+    let queue = Arc::new(Mutex::new(WorkQueue {
+        pending,
+        in_flight: HashMap::new(),
+        parts: HashMap::new(),
+        done: watch::channel(HashSet::new()).0,
+        stats: DownloadStats::default(),
+        picker,
+        endgame,
+        peers: HashMap::new(),
+    }));
+
+    let (events_tx, mut events_rx) = mpsc::channel(MAX_PEERS);
+    // dropped, and so aborted, whenever we return
+    let mut workers = JoinSet::new();
+    let mut spawn = |addr: SocketAddr| {
+        workers.spawn(worker(
+            addr,
+            metainf.clone(),
+            peer_id,
+            dialer,
+            pipeline_depth,
+            queue.clone(),
+            events_tx.clone(),
+        ));
+    };
+    let mut known: HashSet<SocketAddr> = peers.iter().copied().collect();
+    let mut standby: VecDeque<SocketAddr> = peers.iter().skip(MAX_PEERS).copied().collect();
+    let mut active = 0;
+    for addr in peers.iter().take(MAX_PEERS) {
+        spawn(*addr);
+        active += 1;
+    }
+
+    let piece_length = metainf.info.piece_length() as u64;
+    let mut verify_time = Duration::ZERO;
+    let mut bad_writes = 0;
+    while remaining > 0 {
+        // with no peers left, announce again as soon as the tracker allows
+        let wake = reannouncer
+            .as_ref()
+            .map(|r| if active == 0 { r.earliest() } else { r.due() });
+        let wake_at = wake.unwrap_or_else(TokioInstant::now);
+        let event = tokio::select! {
+            event = events_rx.recv() => event,
+            _ = tokio::time::sleep_until(wake_at), if wake.is_some() => None,
+        };
+
+        let (index, piece) = match event {
+            Some(Event::Piece(index, piece)) => (index, piece),
+            Some(Event::Gone(_)) => {
+                active -= 1;
+                if let Some(addr) = standby.pop_front() {
+                    spawn(addr);
+                    active += 1;
+                }
+                if active == 0 && reannouncer.is_none() {
+                    return Err(gave_up(&queue, remaining));
+                }
+                continue;
+            }
+            None => {
+                let Some(r) = reannouncer.as_mut() else {
+                    continue;
+                };
+                let stats = queue.lock().expect("work queue lock poisoned").stats;
+                let fresh: Vec<SocketAddr> = match r.announce(stats.report(*progress)).await {
+                    Ok(found) => found.into_iter().filter(|p| known.insert(*p)).collect(),
+                    Err(e) => {
+                        eprintln!("re-announce failed: {:#}", e);
+                        vec![]
+                    }
+                };
+                eprintln!("re-announce found {} new peers", fresh.len());
+                standby.extend(fresh);
+                while active < MAX_PEERS {
+                    let Some(addr) = standby.pop_front() else {
+                        break;
+                    };
+                    spawn(addr);
+                    active += 1;
+                }
+                if active == 0 {
+                    return Err(gave_up(&queue, remaining));
+                }
+                continue;
+            }
+        };
+
+        let offset = index as u64 * piece_length;
+        write_piece(out, offset, &piece).await.map_err(|e| {
+            Detailed::new(
+                Error::Io(e),
+                format!("fatal disk error writing piece {}", index),
+            )
+            .field("piece", index)
+        })?;
+        if verify_writes {
+            let started = Instant::now();
+            out.sync_at(offset, piece.len()).await?;
+            let on_disk = out.read_at(offset, piece.len()).await?;
+            verify_time += started.elapsed();
+            if !metainf.info.verify_piece(index, &on_disk) {
+                bad_writes += 1;
+                if bad_writes >= MAX_BAD_PIECES {
+                    return Err(Error::Io(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{} pieces read back from disk corrupted", bad_writes),
+                    ))
+                    .into());
+                }
+                eprintln!(
+                    "piece {} read back from disk does not match its hash, fetching it again",
+                    index
+                );
+                queue
+                    .lock()
+                    .expect("work queue lock poisoned")
+                    .redo(index, piece.len());
+                continue;
+            }
+        }
+        progress.downloaded += piece.len() as u64;
+        progress.left -= piece.len() as u64;
+        remaining -= 1;
+        resume.mark(index);
+        resume.downloaded = progress.downloaded;
+        if let Some(r) = reannouncer.as_ref() {
+            resume.set_tracker_ids(r.tracker_ids());
+        }
+        if let Err(e) = resume.save().await {
+            eprintln!("warning: couldn't save resume state: {:#}", e);
+        }
+        eprintln!("piece {} done, {} to go", index, remaining);
+    }
+    let stats = queue.lock().expect("work queue lock poisoned").stats;
+    Ok(DownloadStats {
+        verify_time,
+        ..stats
+    })
+}
+
+fn gave_up(queue: &SharedQueue, remaining: usize) -> anyhow::Error {
+    let missing: Vec<u32> = queue
+        .lock()
+        .expect("work queue lock poisoned")
+        .pending
+        .iter()
+        .copied()
+        .collect();
+    anyhow!(
+        "every peer gave up with {} pieces left: {:?}",
+        remaining,
+        missing
+    )
+}
+
+// what workers report back to download_all
+enum Event {
+    Piece(u32, Vec<u8>),
+    // the worker for this peer has stopped
+    Gone(SocketAddr),
+}
+
+async fn worker(
+    addr: SocketAddr,
+    metainf: Metainfo,
+    peer_id: [u8; 20],
+    dialer: peer::Dialer,
+    pipeline_depth: usize,
+    queue: SharedQueue,
+    events: mpsc::Sender<Event>,
+) {
+    let fetched = fetch_from(
+        addr,
+        &metainf,
+        peer_id,
+        dialer,
+        pipeline_depth,
+        &queue,
+        &events,
+    );
+    if let Err(e) = fetched.await {
+        eprintln!("dropping peer {}: {:#}", addr, e);
+    }
+    {
+        let mut queue = queue.lock().expect("work queue lock poisoned");
+        queue.picker.remove_peer(addr);
+        queue.peers.remove(&addr);
+    }
+    let _ = events.send(Event::Gone(addr)).await;
+}
+
+async fn fetch_from(
+    addr: SocketAddr,
+    metainf: &Metainfo,
+    peer_id: [u8; 20],
+    dialer: peer::Dialer,
+    pipeline_depth: usize,
+    queue: &SharedQueue,
+    events: &mpsc::Sender<Event>,
+) -> anyhow::Result<()> {
+    let mut peer = ready_peer(addr, metainf, peer_id, dialer).await?;
+    if peer.bitfield().iter().all(|b| *b == 0) {
+        return Err(anyhow!("peer has no pieces to give us"));
+    }
+    peer.set_pipeline_depth(pipeline_depth);
+    let mut bad_pieces = 0;
+    let mut done = queue
+        .lock()
+        .expect("work queue lock poisoned")
+        .done
+        .subscribe();
+
+    loop {
+        let next = queue
+            .lock()
+            .expect("work queue lock poisoned")
+            .next_for(&peer, &metainf.info);
+        let (index, part) = match next {
+            Next::Piece(index) => (index, None),
+            Next::Part(index, begin, length) => (index, Some((begin, length))),
+            Next::Wait => {
+                tokio::time::sleep(IDLE_POLL).await;
+                continue;
+            }
+            Next::Finished => return Ok(()),
+        };
+
+        let started = Instant::now();
+        let fetched = match part {
+            None => {
+                eprintln!("fetching piece {} from {}", index, addr);
+                peer.get_piece_unless(index, delivered(&mut done, index))
+                    .await
+            }
+            Some((begin, length)) => {
+                eprintln!(
+                    "fetching {} bytes at {} of piece {} from {}",
+                    length, begin, index, addr
+                );
+                peer.get_part_unless(index, begin, length, delivered(&mut done, index))
+                    .await
+            }
+        };
+        let piece = match fetched {
+            Ok(Some(piece)) => piece,
+            Ok(None) => {
+                eprintln!("piece {} arrived from another peer first", index);
+                continue;
+            }
+            Err(e) => {
+                let mut queue = queue.lock().expect("work queue lock poisoned");
+                match part {
+                    None => queue.requeue(index),
+                    Some((begin, _)) => queue.requeue_part(index, begin),
+                }
+                return Err(e.context(format!("failed fetching piece {}", index)));
+            }
+        };
+        queue
+            .lock()
+            .expect("work queue lock poisoned")
+            .record_delivery(addr, piece.len(), started.elapsed(), peer.block_rtt());
+        // a part is only checked once the rest of its piece is in
+        let piece = match part {
+            None => piece,
+            Some((begin, _)) => {
+                let whole = queue
+                    .lock()
+                    .expect("work queue lock poisoned")
+                    .part_done(index, begin, &piece);
+                match whole {
+                    Some(whole) => whole,
+                    None => continue,
+                }
+            }
+        };
+
+        if !metainf.info.verify_piece(index, &piece) {
+            {
+                let mut queue = queue.lock().expect("work queue lock poisoned");
+                queue.requeue(index);
+                queue.stats.corrupt_bytes += piece.len() as u64;
+            }
+            // any of the peers that sent parts of it could be to blame
+            if part.is_some() {
+                eprintln!("piece {} failed hash verification", index);
+                continue;
+            }
+            bad_pieces += 1;
+            if bad_pieces >= MAX_BAD_PIECES {
+                return Err(anyhow!(
+                    "sent {} pieces that failed hash verification",
+                    bad_pieces
+                ));
+            }
+            eprintln!("piece {} from {} failed hash verification", index, addr);
+            continue;
+        }
+
+        let first = {
+            let mut queue = queue.lock().expect("work queue lock poisoned");
+            let first = queue.complete(index);
+            if !first {
+                queue.stats.redundant_bytes += piece.len() as u64;
+            }
+            first
+        };
+        if first && events.send(Event::Piece(index, piece)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// resolves once any peer has delivered piece `index`
+async fn delivered(done: &mut watch::Receiver<HashSet<u32>>, index: u32) {
+    loop {
+        if done.borrow_and_update().contains(&index) {
+            return;
+        }
+        if done.changed().await.is_err() {
+            // the download is over, and this worker with it
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_disk_errors() {
+        let cases = [
+            (io::Error::from_raw_os_error(ENOSPC), DiskFailure::Full),
+            // EINTR
+            (io::Error::from_raw_os_error(4), DiskFailure::Transient),
+            (io::ErrorKind::Interrupted.into(), DiskFailure::Transient),
+            (io::ErrorKind::WouldBlock.into(), DiskFailure::Transient),
+            (io::ErrorKind::TimedOut.into(), DiskFailure::Transient),
+            // EACCES, EROFS, ENOENT
+            (io::Error::from_raw_os_error(13), DiskFailure::Fatal),
+            (io::Error::from_raw_os_error(30), DiskFailure::Fatal),
+            (io::Error::from_raw_os_error(2), DiskFailure::Fatal),
+            (io::ErrorKind::InvalidData.into(), DiskFailure::Fatal),
+        ];
+        for (e, expected) in cases {
+            assert_eq!(classify(&e), expected, "{}", e);
+        }
+    }
+
+    // a queue with `pending` pieces waiting and `in_flight` ones each being
+    // fetched by one peer
+    fn work_queue(pending: &[u32], in_flight: &[u32]) -> WorkQueue {
+        WorkQueue {
+            pending: pending.iter().copied().collect(),
+            in_flight: in_flight.iter().map(|i| (*i, 1)).collect(),
+            parts: HashMap::new(),
+            done: watch::channel(HashSet::new()).0,
+            stats: DownloadStats::default(),
+            picker: Box::new(picker::InOrder),
+            endgame: Endgame::default(),
+            peers: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn redo_requeues_a_delivered_piece() {
+        let mut queue = work_queue(&[1], &[0]);
+        let done = queue.done.subscribe();
+        assert!(queue.complete(0));
+        assert!(done.borrow().contains(&0));
+
+        queue.redo(0, 16384);
+        assert_eq!(queue.pending, [1, 0]);
+        assert!(!done.borrow().contains(&0));
+        assert_eq!(queue.stats.corrupt_bytes, 16384);
+    }
+
+    #[test]
+    fn parts_come_together_into_a_piece() {
+        let mut queue = work_queue(&[], &[0]);
+        queue.parts.insert(
+            0,
+            PartialPiece {
+                buf: vec![0; 6],
+                unassigned: VecDeque::new(),
+                fetching: HashMap::from([(0, 1), (4, 2)]),
+                missing: HashSet::from([0, 4]),
+            },
+        );
+
+        // a doubled-up part goes back to be handed out only once nobody has it
+        queue.requeue_part(0, 4);
+        assert!(queue.parts[&0].unassigned.is_empty());
+        queue.requeue_part(0, 4);
+        assert_eq!(queue.parts[&0].unassigned, [4]);
+
+        assert_eq!(queue.part_done(0, 4, b"ef"), None);
+        assert_eq!(queue.part_done(0, 4, b"ef"), None);
+        assert_eq!(queue.stats.redundant_bytes, 2);
+        assert_eq!(
+            queue.part_done(0, 0, b"abcd").as_deref(),
+            Some(&b"abcdef"[..])
+        );
+        assert!(queue.parts.is_empty());
+        assert!(queue.complete(0));
+    }
+
+    #[test]
+    fn parses_endgame_settings() {
+        assert_eq!(
+            "now".parse::<EndgameTrigger>().unwrap(),
+            EndgameTrigger::Immediately
+        );
+        assert_eq!(
+            "20".parse::<EndgameTrigger>().unwrap(),
+            EndgameTrigger::Blocks(20)
+        );
+        assert_eq!(
+            "2.5%".parse::<EndgameTrigger>().unwrap(),
+            EndgameTrigger::Percent(2.5)
+        );
+        for bad in ["", "-1", "150%", "x%"] {
+            assert!(bad.parse::<EndgameTrigger>().is_err(), "{}", bad);
+        }
+        assert_eq!(
+            "all".parse::<EndgameStrategy>().unwrap(),
+            EndgameStrategy::AllPeers
+        );
+        assert_eq!(
+            "3".parse::<EndgameStrategy>().unwrap(),
+            EndgameStrategy::Fastest(3)
+        );
+        assert!("0".parse::<EndgameStrategy>().is_err());
+    }
+
+    #[test]
+    fn endgame_waits_for_its_trigger() {
+        // ten pieces of two blocks each, of which 3 and 4 are still to come
+        let info = InfoDict::SingleFile {
+            name: "test".to_string(),
+            piece_length: 32768,
+            pieces: serde_bytes::ByteBuf::from(vec![0; 200]),
+            length: 10 * 32768,
+            private: None,
+        };
+        let mut queue = work_queue(&[3], &[4]);
+        assert!(queue.in_endgame(&info));
+        for (trigger, expected) in [
+            (EndgameTrigger::Blocks(3), false),
+            (EndgameTrigger::Blocks(4), true),
+            (EndgameTrigger::Percent(10.0), false),
+            (EndgameTrigger::Percent(20.0), true),
+        ] {
+            queue.endgame.trigger = trigger;
+            assert_eq!(queue.in_endgame(&info), expected, "{:?}", trigger);
+        }
+    }
+
+    #[test]
+    fn only_the_fastest_peers_double_up() {
+        let peer = |n: u8| SocketAddr::from(([10, 0, 0, n], 6881));
+        let mut queue = work_queue(&[], &[0]);
+        queue.endgame.strategy = EndgameStrategy::Fastest(2);
+        let second = Duration::from_secs(1);
+        queue.record_delivery(peer(1), 100_000, second, None);
+        queue.record_delivery(peer(2), 300_000, second, None);
+        queue.record_delivery(peer(3), 200_000, second, None);
+        assert!(!queue.may_double_up(peer(1)));
+        assert!(queue.may_double_up(peer(2)));
+        assert!(queue.may_double_up(peer(3)));
+        // unmeasured peers rank behind every measured one
+        assert!(!queue.may_double_up(peer(4)));
+
+        // peer 1 speeds up, but one fast piece only moves its average so far
+        queue.record_delivery(peer(1), 1_000_000, second, None);
+        assert_eq!(queue.peers[&peer(1)].rate, Some(370_000.0));
+        assert!(queue.may_double_up(peer(1)));
+        assert!(!queue.may_double_up(peer(3)));
+    }
+
+    #[test]
+    fn slow_peers_keep_to_pieces_fast_ones_lack() {
+        let peer = |n: u8| SocketAddr::from(([10, 0, 0, n], 6881));
+        let mut queue = work_queue(&[0, 1, 2, 3], &[]);
+        let second = Duration::from_secs(1);
+        let ms = Duration::from_millis;
+        // pieces 0..4 are bits 0x80 down to 0x10
+        for (n, rate, rtt, bitfield) in [
+            (1, 1_000_000, ms(40), 0b1100_0000),
+            (2, 900_000, ms(50), 0b1110_0000),
+            (3, 100_000, ms(60), 0b1111_0000),
+            (4, 800_000, ms(400), 0b1011_0000),
+        ] {
+            queue.record_delivery(peer(n), rate, second, Some(rtt));
+            queue.peers.get_mut(&peer(n)).unwrap().bitfield = vec![bitfield];
+        }
+        assert!(!queue.is_slow(peer(1)));
+        assert!(!queue.is_slow(peer(2)));
+        // too little throughput, or too long a round trip
+        assert!(queue.is_slow(peer(3)));
+        assert!(queue.is_slow(peer(4)));
+        // nothing measured yet
+        assert!(!queue.is_slow(peer(5)));
+
+        // only slow peers have piece 3, and only one fast peer has piece 2
+        assert_eq!(queue.least_contended(vec![0, 1, 2, 3]), [3]);
+        assert_eq!(queue.least_contended(vec![0, 1, 2]), [2]);
+        assert_eq!(queue.least_contended(vec![]), Vec::<u32>::new());
+    }
+
+    // three 4 byte pieces over two 6 byte files, so piece 1 spans both
+    fn two_files(data: &[u8; 12]) -> InfoDict {
+        use sha1::{Digest, Sha1};
+        InfoDict::MultiFile {
+            name: "two".to_string(),
+            piece_length: 4,
+            pieces: serde_bytes::ByteBuf::from(
+                data.chunks(4)
+                    .flat_map(|piece| Sha1::digest(piece).to_vec())
+                    .collect::<Vec<u8>>(),
+            ),
+            files: ["a", "b"]
+                .iter()
+                .map(|name| crate::metainfo::InfoDictFile {
+                    length: 6,
+                    path: vec![name.to_string()],
+                })
+                .collect(),
+            private: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn open_reports_missing_and_resized_files() {
+        let data = b"aaaaaabbbbbb";
+        let info = two_files(data);
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("two");
+        let mut storage = Storage::create(&info, &target).await.unwrap();
+        storage.write_at(0, data).await.unwrap();
+        drop(storage);
+
+        let mut storage = Storage::open(&info, &target).await.unwrap();
+        assert!(storage.problems().is_empty());
+        assert_eq!(
+            check_pieces(&info, &mut storage).await.unwrap(),
+            [true, true, true]
+        );
+
+        tokio::fs::remove_file(target.join("b")).await.unwrap();
+        let mut storage = Storage::open(&info, &target).await.unwrap();
+        assert_eq!(storage.problems().len(), 1);
+        assert!(storage.problems()[0].ends_with("b is missing"));
+        assert_eq!(
+            check_pieces(&info, &mut storage).await.unwrap(),
+            [true, false, false]
+        );
+
+        let mut a = OpenOptions::new()
+            .append(true)
+            .open(target.join("a"))
+            .await
+            .unwrap();
+        a.write_all(b"extra").await.unwrap();
+        let storage = Storage::open(&info, &target).await.unwrap();
+        assert!(storage.problems()[0].ends_with("a is 11 bytes, the torrent says 6"));
+        assert!(storage.problems()[1].ends_with("b is missing"));
+    }
+}
@@ -0,0 +1,161 @@
+// strategies for which piece a peer should be asked for next
+use std::{collections::HashMap, net::SocketAddr};
+
+use crate::utils::random_u32;
+
+// pieces are picked at random until this many are done, so there's soon
+// something to trade without everyone starting on the same rare piece
+const RANDOM_FIRST: usize = 4;
+
+pub trait PiecePicker: Send {
+    // `bitfield` is everything `peer` has right now
+    fn update_peer(&mut self, _peer: SocketAddr, _bitfield: &[u8]) {}
+
+    fn remove_peer(&mut self, _peer: SocketAddr) {}
+
+    // chooses from `candidates`, the pieces nobody is fetching that the peer
+    // asking has, in the order they were queued; never called with none
+    fn pick(&mut self, candidates: &[u32]) -> u32;
+
+    fn piece_done(&mut self, _index: u32) {}
+}
+
+// whatever has been waiting longest, which starts out as index order
+pub struct InOrder;
+
+impl PiecePicker for InOrder {
+    fn pick(&mut self, candidates: &[u32]) -> u32 {
+        candidates[0]
+    }
+}
+
+// the piece the fewest connected peers have, ties broken at random, so the
+// pieces most likely to disappear from the swarm are fetched while they can be
+pub struct RarestFirst {
+    availability: Vec<u32>,
+    bitfields: HashMap<SocketAddr, Vec<u8>>,
+    done: usize,
+}
+
+impl RarestFirst {
+    pub fn new(num_pieces: usize) -> Self {
+        RarestFirst {
+            availability: vec![0; num_pieces],
+            bitfields: HashMap::new(),
+            done: 0,
+        }
+    }
+
+    fn count(&mut self, bitfield: &[u8], add: bool) {
+        for (index, n) in self.availability.iter_mut().enumerate() {
+            let has = bitfield
+                .get(index / 8)
+                .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0);
+            if has {
+                *n = if add { *n + 1 } else { n.saturating_sub(1) };
+            }
+        }
+    }
+}
+
+impl PiecePicker for RarestFirst {
+    fn update_peer(&mut self, peer: SocketAddr, bitfield: &[u8]) {
+        if self.bitfields.get(&peer).is_some_and(|b| b == bitfield) {
+            return;
+        }
+        self.remove_peer(peer);
+        self.count(bitfield, true);
+        self.bitfields.insert(peer, bitfield.to_vec());
+    }
+
+    fn remove_peer(&mut self, peer: SocketAddr) {
+        if let Some(old) = self.bitfields.remove(&peer) {
+            self.count(&old, false);
+        }
+    }
+
+    fn pick(&mut self, candidates: &[u32]) -> u32 {
+        let pool: Vec<u32> = if self.done < RANDOM_FIRST {
+            candidates.to_vec()
+        } else {
+            let rarity = |i: &u32| self.availability.get(*i as usize).copied();
+            let rarest = candidates.iter().filter_map(rarity).min();
+            candidates
+                .iter()
+                .copied()
+                .filter(|i| rarity(i) == rarest)
+                .collect()
+        };
+        pool[random_u32() as usize % pool.len()]
+    }
+
+    fn piece_done(&mut self, _index: u32) {
+        self.done += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    // a bitfield for 10 pieces with `pieces` set
+    fn bitfield(pieces: &[usize]) -> Vec<u8> {
+        let mut bf = vec![0u8; 2];
+        for &i in pieces {
+            bf[i / 8] |= 0x80 >> (i % 8);
+        }
+        bf
+    }
+
+    #[test]
+    fn counts_availability() {
+        let mut picker = RarestFirst::new(10);
+        picker.update_peer(peer(1), &bitfield(&[0, 1, 9]));
+        picker.update_peer(peer(2), &bitfield(&[1, 2, 9]));
+        assert_eq!(picker.availability, [1, 2, 1, 0, 0, 0, 0, 0, 0, 2]);
+
+        // the same bitfield again doesn't count twice, a changed one replaces it
+        picker.update_peer(peer(1), &bitfield(&[0, 1, 9]));
+        picker.update_peer(peer(2), &bitfield(&[1, 2, 3, 9]));
+        assert_eq!(picker.availability, [1, 2, 1, 1, 0, 0, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn remove_peer_decrements() {
+        let mut picker = RarestFirst::new(10);
+        picker.update_peer(peer(1), &bitfield(&[0, 1]));
+        picker.update_peer(peer(2), &bitfield(&[1, 2]));
+        picker.remove_peer(peer(1));
+        assert_eq!(picker.availability, [0, 1, 1, 0, 0, 0, 0, 0, 0, 0]);
+        // unknown or already removed peers change nothing
+        picker.remove_peer(peer(1));
+        picker.remove_peer(peer(3));
+        assert_eq!(picker.availability, [0, 1, 1, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn picks_rarest_after_random_first() {
+        let mut picker = RarestFirst::new(10);
+        picker.update_peer(peer(1), &bitfield(&[0, 1, 2, 3]));
+        picker.update_peer(peer(2), &bitfield(&[0, 1, 2]));
+        picker.update_peer(peer(3), &bitfield(&[0, 2]));
+        let candidates = [0, 1, 2, 3];
+
+        for index in 0..RANDOM_FIRST as u32 {
+            assert!(candidates.contains(&picker.pick(&candidates)));
+            picker.piece_done(index + 4);
+        }
+        // piece 3 is only on one peer
+        for _ in 0..20 {
+            assert_eq!(picker.pick(&candidates), 3);
+        }
+        // among equally rare pieces, any of them
+        for _ in 0..20 {
+            assert!([0, 2].contains(&picker.pick(&[0, 2])));
+        }
+    }
+}
@@ -0,0 +1,251 @@
+// what an interrupted download had finished, kept in a small bencoded file
+// next to its output so re-running it picks up where it stopped
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use super::Storage;
+use crate::metainfo::InfoDict;
+
+#[derive(Serialize, Deserialize)]
+pub struct ResumeState {
+    #[serde(rename = "info hash")]
+    info_hash: ByteBuf,
+    // pieces that were verified and written
+    bitfield: ByteBuf,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    // tracker URL => the 'tracker id' it gave us, to keep sending it
+    #[serde(rename = "tracker ids", default)]
+    tracker_ids: BTreeMap<String, ByteBuf>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl ResumeState {
+    // `target` is the download's output path, file or directory
+    pub fn new(target: &Path, info_hash: [u8; 20], num_pieces: usize) -> Self {
+        ResumeState {
+            info_hash: ByteBuf::from(info_hash.to_vec()),
+            bitfield: ByteBuf::from(vec![0; num_pieces.div_ceil(8)]),
+            downloaded: 0,
+            uploaded: 0,
+            tracker_ids: BTreeMap::new(),
+            path: resume_path(target),
+        }
+    }
+
+    // the state saved for `target`, if there is any and it's for this torrent
+    pub async fn load(
+        target: &Path,
+        info_hash: [u8; 20],
+        num_pieces: usize,
+    ) -> anyhow::Result<Option<Self>> {
+        let path = resume_path(target);
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("error reading {}", path.display()));
+            }
+        };
+        let state: ResumeState = match serde_bencode::from_bytes(&bytes) {
+            Ok(state) => state,
+            Err(e) => {
+                eprintln!(
+                    "warning: ignoring unreadable resume file {}: {}",
+                    path.display(),
+                    e
+                );
+                return Ok(None);
+            }
+        };
+        if state.info_hash.as_slice() != info_hash || state.bitfield.len() != num_pieces.div_ceil(8)
+        {
+            eprintln!(
+                "warning: ignoring resume file {}, it is for another torrent",
+                path.display()
+            );
+            return Ok(None);
+        }
+        Ok(Some(ResumeState { path, ..state }))
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.bitfield
+            .get(index as usize / 8)
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    pub fn tracker_ids(&self) -> BTreeMap<String, Vec<u8>> {
+        self.tracker_ids
+            .iter()
+            .map(|(url, id)| (url.clone(), id.to_vec()))
+            .collect()
+    }
+
+    pub fn set_tracker_ids(&mut self, ids: &BTreeMap<String, Vec<u8>>) {
+        self.tracker_ids = ids
+            .iter()
+            .map(|(url, id)| (url.clone(), ByteBuf::from(id.clone())))
+            .collect();
+    }
+
+    pub fn mark(&mut self, index: u32) {
+        if let Some(byte) = self.bitfield.get_mut(index as usize / 8) {
+            *byte |= 0x80 >> (index % 8);
+        }
+    }
+
+    fn unmark(&mut self, index: u32) {
+        if let Some(byte) = self.bitfield.get_mut(index as usize / 8) {
+            *byte &= !(0x80 >> (index % 8));
+        }
+    }
+
+    // re-hashes every piece marked as done, forgetting the ones the files on
+    // disk no longer match; returns how many are left
+    pub async fn verify(&mut self, info: &InfoDict, storage: &mut Storage) -> anyhow::Result<u32> {
+        let mut kept = 0;
+        for index in 0..info.num_pieces() as u32 {
+            if !self.has_piece(index) {
+                continue;
+            }
+            let piece = storage
+                .read_at(
+                    index as u64 * info.piece_length() as u64,
+                    info.piece_size(index) as usize,
+                )
+                .await
+                .with_context(|| format!("error reading piece {}", index))?;
+            if info.verify_piece(index, &piece) {
+                kept += 1;
+            } else {
+                eprintln!("piece {} changed on disk since it was saved", index);
+                self.unmark(index);
+            }
+        }
+        Ok(kept)
+    }
+
+    // written to a temporary file first so a crash can't leave half of it
+    pub async fn save(&self) -> anyhow::Result<()> {
+        let bytes = serde_bencode::to_bytes(self)?;
+        let tmp = self.path.with_extension("resume.tmp");
+        tokio::fs::write(&tmp, bytes)
+            .await
+            .with_context(|| format!("error writing {}", tmp.display()))?;
+        tokio::fs::rename(&tmp, &self.path)
+            .await
+            .with_context(|| format!("error replacing {}", self.path.display()))
+    }
+
+    // once the download is complete there's nothing left to resume
+    pub async fn remove(&self) -> anyhow::Result<()> {
+        match tokio::fs::remove_file(&self.path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("error removing {}", self.path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// beside the output rather than in it, even when the output is a directory
+fn resume_path(target: &Path) -> PathBuf {
+    let mut name = target.file_name().unwrap_or_default().to_owned();
+    name.push(".resume");
+    target.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: [u8; 20] = [7; 20];
+
+    #[test]
+    fn resume_file_sits_beside_the_output() {
+        assert_eq!(
+            resume_path(Path::new("/data/out.iso")),
+            Path::new("/data/out.iso.resume")
+        );
+        assert_eq!(
+            resume_path(Path::new("/data/album/")),
+            Path::new("/data/album.resume")
+        );
+        assert_eq!(resume_path(Path::new("out")), Path::new("out.resume"));
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out");
+        let mut state = ResumeState::new(&target, HASH, 10);
+        state.mark(0);
+        state.mark(9);
+        state.downloaded = 1234;
+        state.uploaded = 56;
+        let ids = BTreeMap::from([("http://a/announce".to_string(), b"\x00id".to_vec())]);
+        state.set_tracker_ids(&ids);
+        state.save().await.unwrap();
+
+        let loaded = ResumeState::load(&target, HASH, 10).await.unwrap().unwrap();
+        let done: Vec<u32> = (0..10).filter(|i| loaded.has_piece(*i)).collect();
+        assert_eq!(done, [0, 9]);
+        assert_eq!(loaded.downloaded, 1234);
+        assert_eq!(loaded.uploaded, 56);
+        assert_eq!(loaded.tracker_ids(), ids);
+
+        loaded.remove().await.unwrap();
+        assert!(ResumeState::load(&target, HASH, 10)
+            .await
+            .unwrap()
+            .is_none());
+        // removing what isn't there is fine
+        loaded.remove().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_state_for_another_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out");
+        assert!(ResumeState::load(&target, HASH, 10)
+            .await
+            .unwrap()
+            .is_none());
+
+        ResumeState::new(&target, HASH, 10).save().await.unwrap();
+        assert!(ResumeState::load(&target, [8; 20], 10)
+            .await
+            .unwrap()
+            .is_none());
+        // 10 and 17 pieces need bitfields of different lengths
+        assert!(ResumeState::load(&target, HASH, 17)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(ResumeState::load(&target, HASH, 10)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn ignores_unreadable_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out");
+        tokio::fs::write(resume_path(&target), b"not bencode")
+            .await
+            .unwrap();
+        assert!(ResumeState::load(&target, HASH, 10)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,135 @@
+use std::{fmt, io};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid bencode: {0}")]
+    Bencode(String),
+    #[error("invalid metainfo: {0}")]
+    Metainfo(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("tracker request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("tracker failure: {0}")]
+    Tracker(String),
+    #[error("peer protocol violation: {0}")]
+    Protocol(String),
+    #[error("{0}")]
+    Usage(String),
+    #[error("interrupted")]
+    Interrupted,
+}
+
+impl Error {
+    // bad invocations get the conventional usage exit code, everything else 1
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => 2,
+            // 128 + SIGINT, as shells report it
+            Error::Interrupted => 130,
+            _ => 1,
+        }
+    }
+
+    // a stable machine-readable name for the kind of failure
+    pub fn category(&self) -> &'static str {
+        match self {
+            Error::Bencode(_) => "bencode",
+            Error::Metainfo(_) => "metainfo",
+            Error::Io(_) => "io",
+            Error::Http(_) => "http",
+            Error::Tracker(_) => "tracker",
+            Error::Protocol(_) => "protocol",
+            Error::Usage(_) => "usage",
+            Error::Interrupted => "interrupted",
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// context for an error that also names what it was about (a tracker URL, an
+// info hash, a piece index) as fields, so --json output can report them as keys
+// instead of leaving them inside the message
+#[derive(Debug)]
+pub struct Detailed {
+    message: String,
+    fields: Vec<(&'static str, serde_json::Value)>,
+    source: anyhow::Error,
+}
+
+impl Detailed {
+    pub fn new(source: impl Into<anyhow::Error>, message: impl Into<String>) -> Self {
+        Detailed {
+            message: message.into(),
+            fields: vec![],
+            source: source.into(),
+        }
+    }
+
+    pub fn field(mut self, key: &'static str, value: impl Into<serde_json::Value>) -> Self {
+        self.fields.push((key, value.into()));
+        self
+    }
+
+    pub fn fields(&self) -> &[(&'static str, serde_json::Value)] {
+        &self.fields
+    }
+
+    // every field attached anywhere in `e`'s chain; where the same key appears
+    // more than once the outermost wins
+    pub fn collect(e: &anyhow::Error) -> serde_json::Map<String, serde_json::Value> {
+        let mut all = serde_json::Map::new();
+        for detailed in e.chain().filter_map(|c| c.downcast_ref::<Detailed>()) {
+            for (key, value) in detailed.fields() {
+                all.entry(key.to_string()).or_insert_with(|| value.clone());
+            }
+        }
+        all
+    }
+}
+
+impl fmt::Display for Detailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Detailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn detailed_fields_survive_context() {
+        let inner: anyhow::Error = Detailed::new(Error::Tracker("busy".into()), "tracker failed")
+            .field("url", "http://a/announce")
+            .into();
+        let e = Err::<(), _>(inner)
+            .context("every tracker failed")
+            .map_err(|e| {
+                Detailed::new(e, "downloading x.torrent")
+                    .field("info_hash", "00ff")
+                    .field("url", "http://outer/")
+            })
+            .unwrap_err();
+        let e = anyhow::Error::from(e);
+
+        assert_eq!(
+            format!("{:#}", e),
+            "downloading x.torrent: every tracker failed: tracker failed: tracker failure: busy"
+        );
+        let fields = Detailed::collect(&e);
+        assert_eq!(fields["info_hash"], "00ff");
+        assert_eq!(fields["url"], "http://outer/");
+        // the typed error underneath is still found for the exit code
+        assert!(e.chain().any(|c| c.downcast_ref::<Error>().is_some()));
+    }
+}
@@ -0,0 +1,9 @@
+pub mod bencode;
+pub mod download;
+pub mod error;
+pub mod magnet;
+pub mod metainfo;
+pub mod peer;
+pub mod seed;
+pub mod tracker;
+pub mod utils;
@@ -0,0 +1,213 @@
+use std::{net::SocketAddr, str::FromStr};
+
+use crate::{
+    error::{Error, Result},
+    metainfo::{InfoDict, Metainfo},
+};
+
+// a parsed `magnet:?xt=urn:btih:...` URI
+#[derive(Debug, Clone, PartialEq)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    // "dn": a name suggestion for display while the metadata is unknown
+    pub display_name: Option<String>,
+    // "tr": tracker URLs, in the order given
+    pub trackers: Vec<String>,
+    // "x.pe": peers to connect to directly
+    pub peers: Vec<SocketAddr>,
+}
+
+impl FromStr for MagnetLink {
+    type Err = Error;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or_else(|| bad(format!("{:?} does not start with magnet:?", uri)))?;
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).map_err(|e| bad(e.to_string()))?;
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = vec![];
+        let mut peers = vec![];
+        for (key, value) in params {
+            match key.as_str() {
+                "xt" => {
+                    // other hash types (e.g. btmh for v2) can appear alongside btih
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_btih(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => peers.push(
+                    SocketAddr::from_str(&value)
+                        .map_err(|_| bad(format!("invalid peer address {:?}", value)))?,
+                ),
+                _ => {}
+            }
+        }
+
+        Ok(MagnetLink {
+            info_hash: info_hash.ok_or_else(|| bad("no urn:btih info hash".to_string()))?,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+impl MagnetLink {
+    // the metainfo a .torrent file for this link would have held, given the
+    // info dict fetched from peers and the exact bytes it arrived as
+    pub fn to_metainfo(&self, info: InfoDict, info_bytes: Vec<u8>) -> Metainfo {
+        let mut metainfo = Metainfo::from_info(info, info_bytes);
+        metainfo.announce = self.trackers.first().cloned().unwrap_or_default();
+        if self.trackers.len() > 1 {
+            metainfo.announce_list = vec![self.trackers.clone()];
+        }
+        metainfo
+    }
+}
+
+// btih hashes are either 40 hex digits or 32 base32 characters
+fn parse_btih(hash: &str) -> Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).ok(),
+        32 => base32(hash),
+        _ => None,
+    };
+    bytes
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| bad(format!("invalid info hash {:?}", hash)))
+}
+
+// RFC 4648 base32 without padding
+fn base32(s: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let mut acc: u64 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let v = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        acc = (acc << 5) | v as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn bad(msg: String) -> Error {
+    Error::Usage(format!("invalid magnet link: {}", msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "ad42ce8109f54c99613ce38f9b4d87e70f24a165";
+
+    fn hash() -> [u8; 20] {
+        hex::decode(HASH).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648() {
+        for (encoded, decoded) in [
+            ("", &b""[..]),
+            ("MY", b"f"),
+            ("MZXQ", b"fo"),
+            ("MZXW6", b"foo"),
+            ("MZXW6YQ", b"foob"),
+            ("MZXW6YTB", b"fooba"),
+            ("MZXW6YTBOI", b"foobar"),
+            ("mzxw6ytboi", b"foobar"),
+        ] {
+            assert_eq!(base32(encoded).as_deref(), Some(decoded), "{}", encoded);
+        }
+        assert_eq!(base32("MZXW6YTB0I"), None);
+        assert_eq!(base32("MZXW6YTB=="), None);
+    }
+
+    #[test]
+    fn btih_in_hex_or_base32() {
+        assert_eq!(parse_btih(HASH).unwrap(), hash());
+        assert_eq!(parse_btih(&HASH.to_uppercase()).unwrap(), hash());
+        assert_eq!(
+            parse_btih("VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF").unwrap(),
+            hash()
+        );
+        for bad in [
+            &HASH[..39],
+            "zd42ce8109f54c99613ce38f9b4d87e70f24a165",
+            "VVBM5AIJ6VGJSYJ44OHZWTMH44HSJIL1",
+            "",
+        ] {
+            assert!(parse_btih(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn parses_trackers_and_peers() {
+        let link: MagnetLink = format!(
+            "magnet:?xt=urn:btih:{}&dn=magnet1.gif\
+             &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce\
+             &tr=udp%3A%2F%2Ftracker.example%3A6969\
+             &x.pe=10.0.0.1:6881&x.pe=[2001:db8::1]:51413",
+            HASH
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(
+            link,
+            MagnetLink {
+                info_hash: hash(),
+                display_name: Some("magnet1.gif".to_string()),
+                trackers: vec![
+                    "http://bittorrent-test-tracker.codecrafters.io/announce".to_string(),
+                    "udp://tracker.example:6969".to_string(),
+                ],
+                peers: vec![
+                    "10.0.0.1:6881".parse().unwrap(),
+                    "[2001:db8::1]:51413".parse().unwrap(),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn ignores_other_hash_types() {
+        let link: MagnetLink = format!(
+            "magnet:?xt=urn:btmh:1220{}&xt=urn:btih:{}",
+            "00".repeat(32),
+            HASH
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(link.info_hash, hash());
+        assert!(link.trackers.is_empty());
+        assert!(link.peers.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_links() {
+        for uri in [
+            format!("http://example.com/?xt=urn:btih:{}", HASH),
+            format!("magnet:xt=urn:btih:{}", HASH),
+            "magnet:?dn=no-hash".to_string(),
+            "magnet:?xt=urn:btmh:1220abcd".to_string(),
+            "magnet:?xt=urn:btih:1234".to_string(),
+            format!("magnet:?xt=urn:btih:{}&x.pe=10.0.0.1", HASH),
+            format!("magnet:?xt=urn:btih:{}&x.pe=example.com:6881", HASH),
+        ] {
+            assert!(uri.parse::<MagnetLink>().is_err(), "{}", uri);
+        }
+    }
+}
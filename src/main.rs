@@ -1,4 +1,12 @@
-use std::{arch::x86_64::_rdrand32_step, env, net::SocketAddr, str::FromStr};
+use std::{
+    arch::x86_64::_rdrand32_step,
+    env,
+    io::{stderr, stdout},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Context;
 use tokio::{
@@ -6,16 +14,55 @@ use tokio::{
     io::AsyncWriteExt,
 };
 
-mod peer;
-mod tracker;
-mod types;
-mod utils;
+use bittorrent_starter_rust::{
+    bencode, download,
+    error::{Detailed, Error},
+    magnet, metainfo, peer, seed, tracker, utils,
+};
+
+const PROBE_TIMEOUT_SECS: u64 = 2;
 
 // Usage: your_bittorrent.sh decode "<encoded_value>"
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() {
     let args: Vec<String> = env::args().collect();
-    let command = &args[1];
+    let color = utils::color_enabled(stderr(), &args);
+    let json = args.iter().any(|a| a == "--json");
+    if let Err(e) = run(args).await {
+        // a typed error somewhere in the chain decides the exit code
+        let typed = e.chain().find_map(|c| c.downcast_ref::<Error>());
+        let code = typed.map_or(1, Error::exit_code);
+        if json {
+            // outermost message first, then each underlying cause; fields
+            // like the tracker URL or piece index get keys of their own
+            let mut report = Detailed::collect(&e);
+            report.insert("code".into(), code.into());
+            report.insert(
+                "category".into(),
+                typed.map_or("other", Error::category).into(),
+            );
+            report.insert("message".into(), e.to_string().into());
+            report.insert(
+                "context".into(),
+                e.chain()
+                    .skip(1)
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .into(),
+            );
+            let report = serde_json::Value::Object(report);
+            eprintln!("{}", report);
+        } else {
+            eprintln!("{} {:#}", utils::paint("Error:", utils::RED, color), e);
+        }
+        std::process::exit(code);
+    }
+}
+
+async fn run(args: Vec<String>) -> anyhow::Result<()> {
+    let command = arg(&args, 1, "a command")?;
+    let rest = args.get(3..).unwrap_or_default();
+    let color = utils::color_enabled(stdout(), &args);
 
     let mut peer_id = [0u8; 20];
     for idx in 0..5 {
@@ -26,38 +73,196 @@ async fn main() -> anyhow::Result<()> {
         peer_id[idx * 4..idx * 4 + 4].copy_from_slice(&randval.to_le_bytes());
     }
 
+    // --bind <ip> pins all tracker and peer traffic for this torrent to one local address
+    let bind_addr = flag_values(&args, "--bind")
+        .first()
+        .map(|a| IpAddr::from_str(a))
+        .transpose()
+        .context("failed to parse --bind address")?;
+    // --peer-proxy <ip:port> tunnels peer connections (not tracker requests) through SOCKS5;
+    // for TLS to a remote relay, point it at a local tunnel such as stunnel or `ssh -D`
+    let dialer = peer::Dialer {
+        local: bind_addr,
+        proxy: flag_values(&args, "--peer-proxy")
+            .first()
+            .map(|a| SocketAddr::from_str(a))
+            .transpose()
+            .context("failed to parse --peer-proxy address")?,
+    };
+
+    // --pipeline <n> sets how many block requests are kept outstanding per peer
+    let pipeline_depth = flag_values(&args, "--pipeline")
+        .first()
+        .map(|n| n.parse::<usize>())
+        .transpose()
+        .context("failed to parse --pipeline depth")?
+        .unwrap_or(peer::DEFAULT_PIPELINE_DEPTH);
+
+    // --endgame-at <now|blocks|percent%> sets when idle peers start doubling up on the
+    // last pieces, and --endgame-peers <all|n> whether all of them do or the n fastest
+    let endgame = download::Endgame {
+        trigger: flag_values(&args, "--endgame-at")
+            .first()
+            .map(|a| a.parse())
+            .transpose()
+            .context("failed to parse --endgame-at")?
+            .unwrap_or(download::EndgameTrigger::Immediately),
+        strategy: flag_values(&args, "--endgame-peers")
+            .first()
+            .map(|a| a.parse())
+            .transpose()
+            .context("failed to parse --endgame-peers")?
+            .unwrap_or(download::EndgameStrategy::AllPeers),
+    };
+
     match command.trim() {
         "decode" => {
             let deser: serde_bencode::value::Value =
-                serde_bencode::from_str(&args[2]).expect("could not deserialize value");
+                serde_bencode::from_str(arg(&args, 2, "an encoded value")?)
+                    .map_err(|e| Error::Bencode(e.to_string()))?;
             let json = utils::convert_bencode_to_json(deser)?;
             println!("{}", json);
             Ok(())
         }
         "peers" => {
-            let torrent = types::Metainfo::from_file(&args[2])
+            let torrent = metainfo::Metainfo::from_file(arg(&args, 2, "a torrent file")?)
                 .await
                 .context("failed reading metainfo")?;
 
-            eprintln!("fetching peers from tracker at {}", torrent.announce);
-            let peers = tracker::announce(
-                &torrent.announce,
-                torrent.info.length(),
-                torrent.info.hash()?,
-                peer_id,
-            )
-            .await?;
-            for p in peers.iter() {
-                println!("{}", p);
+            let tracker_overrides = flag_values(rest, "--tracker");
+            let show_tracker = !tracker_overrides.is_empty();
+
+            let mut results = vec![];
+            if show_tracker {
+                for tracker_addr in tracker_overrides {
+                    eprintln!("fetching peers from tracker at {}", tracker_addr);
+                    match tracker::announce(
+                        &tracker_addr,
+                        tracker::Progress::starting(torrent.info.length()),
+                        torrent.info_hash()?,
+                        peer_id,
+                        bind_addr,
+                        None,
+                    )
+                    .await
+                    {
+                        Ok(announced) => results.push((tracker_addr, announced)),
+                        Err(e) => eprintln!("tracker {} failed: {:#}", tracker_addr, e),
+                    }
+                }
+                if results.is_empty() {
+                    anyhow::bail!("none of the given trackers returned peers");
+                }
+            } else {
+                let mut tiers =
+                    tracker::TrackerTiers::new(&torrent.announce, &torrent.announce_list);
+                results.push(
+                    tiers
+                        .announce(
+                            tracker::Progress::starting(torrent.info.length()),
+                            torrent.info_hash()?,
+                            peer_id,
+                            bind_addr,
+                        )
+                        .await?,
+                );
+            }
+
+            let mut found = vec![];
+            for (tracker_addr, announced) in results {
+                if let Some(ip) = announced.external_ip {
+                    eprintln!("tracker {} sees us as {}", tracker_addr, ip);
+                }
+                if announced.discarded.total() > 0 {
+                    eprintln!(
+                        "discarded {} peers from {}: {:?}",
+                        announced.discarded.total(),
+                        tracker_addr,
+                        announced.discarded
+                    );
+                }
+                found.extend(
+                    announced
+                        .peers
+                        .into_iter()
+                        .map(|p| (p, tracker_addr.clone())),
+                );
+            }
+
+            if rest.iter().any(|a| a == "--table") {
+                print_peer_table(
+                    &found,
+                    show_tracker,
+                    color,
+                    torrent.info_hash()?,
+                    peer_id,
+                    dialer,
+                )
+                .await;
+            } else {
+                for (p, tracker_addr) in found.iter() {
+                    if show_tracker {
+                        println!("{}\t{}", p, tracker_addr);
+                    } else {
+                        println!("{}", p);
+                    }
+                }
+            }
+            Ok(())
+        }
+        "announce" => {
+            // `--dry-run` may come before or after the torrent
+            let dry_run = args.iter().any(|a| a == "--dry-run");
+            let mi_file = args
+                .get(2..)
+                .unwrap_or_default()
+                .iter()
+                .find(|a| *a != "--dry-run")
+                .ok_or_else(|| Error::Usage("usage: announce [--dry-run] <torrent>".into()))?;
+            let torrent = metainfo::Metainfo::from_file(mi_file)
+                .await
+                .context("failed reading metainfo")?;
+            // the same first announce a download sends
+            let progress = tracker::Progress {
+                event: Some(tracker::AnnounceEvent::Started),
+                ..tracker::Progress::starting(torrent.info.length())
+            };
+            let mut tiers = tracker::TrackerTiers::new(&torrent.announce, &torrent.announce_list);
+
+            if dry_run {
+                // every tracker in the order they'd be tried, nothing sent
+                for tracker_addr in tiers.tiers().iter().flatten() {
+                    if tracker_addr.starts_with("udp://") {
+                        println!("{}\t(UDP: parameters go in a binary packet)", tracker_addr);
+                        continue;
+                    }
+                    let url = tracker::announce_url(
+                        tracker_addr,
+                        progress,
+                        torrent.info_hash()?,
+                        peer_id,
+                        bind_addr,
+                        None,
+                    )?;
+                    println!("{}", url);
+                }
+                return Ok(());
             }
+
+            let (tracker_addr, announced) = tiers
+                .announce(progress, torrent.info_hash()?, peer_id, bind_addr)
+                .await?;
+            println!("Tracker: {}", tracker_addr);
+            println!("Interval: {}s", announced.interval.as_secs());
+            println!("Peers: {}", announced.peers.len());
             Ok(())
         }
         "peers2" => {
-            let torrent = types::Metainfo::from_file(&args[2])
+            let torrent = metainfo::Metainfo::from_file(arg(&args, 2, "a torrent file")?)
                 .await
                 .context("failed reading metainfo")?;
 
-            let mut tracker_addr = torrent.announce;
+            let mut tracker_addr = torrent.announce.clone();
 
             if !torrent.announce_list.is_empty() {
                 let http_trackers = torrent
@@ -70,62 +275,127 @@ async fn main() -> anyhow::Result<()> {
                             .collect::<Vec<String>>()
                     })
                     .collect::<Vec<String>>();
-                if !http_trackers.is_empty() {
-                    tracker_addr = http_trackers.first().unwrap().to_string();
+                if let Some(first) = http_trackers.first() {
+                    tracker_addr = first.to_string();
                 }
             }
 
             eprintln!("fetching peers from tracker at {}", tracker_addr);
             let peers = tracker::announce(
                 &tracker_addr,
-                torrent.info.length(),
-                torrent.info.hash()?,
+                tracker::Progress::starting(torrent.info.length()),
+                torrent.info_hash()?,
                 peer_id,
+                bind_addr,
+                None,
             )
-            .await?;
+            .await?
+            .peers;
             for p in peers.iter() {
                 println!("{}", p);
             }
             Ok(())
         }
         "info" => {
-            let metainf = types::Metainfo::from_file(&args[2])
+            let policy = if rest.iter().any(|a| a == "--strict") {
+                bencode::KeyPolicy::Strict
+            } else if rest.iter().any(|a| a == "--keep-first") {
+                bencode::KeyPolicy::KeepFirst
+            } else {
+                bencode::KeyPolicy::KeepLast
+            };
+            // a bare info hash that isn't also a file name is looked up in the
+            // metadata cache that magnet commands fill
+            let source = arg(&args, 2, "a torrent file or info hash")?;
+            let cached_hash = hex::decode(source)
+                .ok()
+                .and_then(|h| <[u8; 20]>::try_from(h).ok())
+                .filter(|_| !Path::new(source).exists());
+            let metainf = match cached_hash {
+                Some(_) if rest.iter().any(|a| a == "--fetch") => {
+                    return Err(Error::Usage(
+                        "fetching metadata by info hash alone needs DHT, which isn't supported; \
+                         use magnet_info with a magnet link instead"
+                            .into(),
+                    )
+                    .into());
+                }
+                Some(hash) => metainfo::Metainfo::from_cache(hash).await?,
+                None => metainfo::Metainfo::from_file_with_policy(source, policy)
+                    .await
+                    .context("failed to read metainfo file")?,
+            };
+            print_info(&metainf, color)
+        }
+        "magnet_info" => {
+            let link: magnet::MagnetLink = arg(&args, 2, "a magnet link")?.parse()?;
+            let peers = download::magnet_peers(&link, peer_id, bind_addr).await?;
+            let (metainf, _) = download::magnet_metainfo(&link, &peers, peer_id, dialer).await?;
+            print_info(&metainf, color)
+        }
+        "info2" => {
+            let metainf = metainfo::Metainfo::from_file(arg(&args, 2, "a torrent file")?)
                 .await
                 .context("failed to read metainfo file")?;
-            println!("Tracker URL: {}", metainf.announce);
-            println!("Length: {}", metainf.info.length());
-            println!("Info Hash: {}", hex::encode(metainf.info.hash()?));
-            println!("Piece Length: {}", metainf.info.piece_length());
-            println!("Piece Hashes:");
+            let label = |l: &str| utils::paint(l, utils::BOLD, color);
+            println!("{} {}", label("Tracker URL:"), metainf.announce);
+            println!("{}\n{:?}", label("Tracker URLs:"), metainf.announce_list);
+            println!("{} {}", label("Length:"), metainf.info.length());
+            println!(
+                "{} {}",
+                label("Info Hash:"),
+                hex::encode(metainf.info_hash()?)
+            );
+            println!("{} {}", label("Piece Length:"), metainf.info.piece_length());
+            println!("{}", label("Piece Hashes:"));
             for ph in metainf.info.pieces().chunks(20).map(Vec::from) {
                 println!("{}", hex::encode(ph));
             }
             Ok(())
         }
-        "info2" => {
-            let metainf = types::Metainfo::from_file(&args[2])
-                .await
-                .context("failed to read metainfo file")?;
-            println!("Tracker URL: {}", metainf.announce);
-            println!("Tracker URLs:\n{:?}", metainf.announce_list);
-            println!("Length: {}", metainf.info.length());
-            println!("Info Hash: {}", hex::encode(metainf.info.hash()?));
-            println!("Piece Length: {}", metainf.info.piece_length());
-            println!("Piece Hashes:");
-            for ph in metainf.info.pieces().chunks(20).map(Vec::from) {
-                println!("{}", hex::encode(ph));
+        "magnet_parse" => {
+            let link: magnet::MagnetLink = arg(&args, 2, "a magnet link")?.parse()?;
+            let label = |l: &str| utils::paint(l, utils::BOLD, color);
+            for tr in link.trackers.iter() {
+                println!("{} {}", label("Tracker URL:"), tr);
+            }
+            println!("{} {}", label("Info Hash:"), hex::encode(link.info_hash));
+            if let Some(name) = link.display_name {
+                println!("{} {}", label("Name:"), name);
+            }
+            for p in link.peers.iter() {
+                println!("{} {}", label("Peer:"), p);
+            }
+            Ok(())
+        }
+        "magnet_handshake" => {
+            let link: magnet::MagnetLink = arg(&args, 2, "a magnet link")?.parse()?;
+            let peers = download::magnet_peers(&link, peer_id, bind_addr).await?;
+
+            eprintln!("starting connection to peer {}", peers[0]);
+            let mut peer =
+                peer::PeerState::connect_by_hash(peers[0], link.info_hash, peer_id, dialer)
+                    .await
+                    .context("failed to connect to peer")?;
+            peer.wait_for_handshake().await?;
+            let extensions = peer.exchange_extension_handshakes().await?;
+
+            println!("Peer ID: {}", hex::encode(peer.remote_peer_id()));
+            match extensions.ut_metadata() {
+                Some(id) => println!("Peer Metadata Extension ID: {}", id),
+                None => println!("Peer Metadata Extension ID: (not supported)"),
             }
             Ok(())
         }
         "handshake" => {
-            let metainf = types::Metainfo::from_file(&args[2])
+            let metainf = metainfo::Metainfo::from_file(arg(&args, 2, "a torrent file")?)
                 .await
                 .context("failed to read metainfo file")?;
-            let peer_addr =
-                SocketAddr::from_str(&args[3]).context("failed to parse given peer address")?;
+            let peer_addr = SocketAddr::from_str(arg(&args, 3, "a peer address")?)
+                .context("failed to parse given peer address")?;
 
             eprintln!("starting connection to peer {}", peer_addr);
-            let mut peer = peer::PeerState::connect(peer_addr, &metainf)
+            let mut peer = peer::PeerState::connect(peer_addr, &metainf, peer_id, dialer)
                 .await
                 .context("failed to connect to peer")?;
 
@@ -133,69 +403,132 @@ async fn main() -> anyhow::Result<()> {
             println!("Peer ID: {}", hex::encode(peer.remote_peer_id()));
             Ok(())
         }
-        "download_piece" => {
-            assert_eq!(
-                args[2],
-                "-o".to_string(),
-                "output must be specified with -o <filepath> as 2nd & 3rd args"
-            );
-
-            let outfile = &args[3];
-            let mi_file = &args[4];
-            let piece_idx = &args[5];
-
-            let metainf = types::Metainfo::from_file(mi_file)
-                .await
-                .context("failed to read metainfo file")?;
-
-            // tracker contact
+        "probe" => {
+            let peer_addr = SocketAddr::from_str(arg(&args, 2, "a peer address")?)
+                .context("failed to parse given peer address")?;
+            let info_hash: [u8; 20] = hex::decode(arg(&args, 3, "an info hash")?)
+                .context("info hash must be hex")?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("info hash must be 20 bytes"))?;
 
-            eprintln!("fetching peers from tracker at {}", metainf.announce);
-            let peers = tracker::announce(
-                &metainf.announce,
-                metainf.info.length(),
-                metainf.info.hash()?,
+            let report = peer::inspect(
+                peer_addr,
+                info_hash,
                 peer_id,
+                dialer,
+                Duration::from_secs(PROBE_TIMEOUT_SECS),
             )
             .await?;
 
-            // handshake begin
-
-            let mut peer = peer::PeerState::connect(peers[0], &metainf).await?;
-            eprintln!("waiting for handshake");
-            peer.wait_for_handshake().await?;
-
-            eprintln!("checking if i have peer's bitfield");
-            while peer.bitfield().is_empty() {
-                let msgs = peer.poll().await?;
-                if msgs.is_empty() {
-                    eprintln!("got nothing from peer this round");
-                } else {
-                    for m in msgs {
-                        eprintln!("waiting for bitfield, got: {:?}", m);
-                    }
+            let label = |l: &str| utils::paint(l, utils::BOLD, color);
+            println!("{} {}", label("Peer ID:"), hex::encode(report.peer_id));
+            println!(
+                "{} {}",
+                label("Client:"),
+                peer::client_name(&report.peer_id).unwrap_or_else(|| "(unknown)".to_string())
+            );
+            println!("{} {}", label("Reserved:"), hex::encode(report.reserved));
+            println!(
+                "{} extension protocol: {}, fast: {}, dht: {}",
+                label("Supports:"),
+                report.supports_extensions(),
+                report.supports_fast(),
+                report.supports_dht()
+            );
+            match &report.bitfield {
+                Some(bf) => println!(
+                    "{} {} of {} bits set",
+                    label("Bitfield:"),
+                    bf.iter().map(|b| b.count_ones()).sum::<u32>(),
+                    bf.len() * 8
+                ),
+                None => println!("{} not sent", label("Bitfield:")),
+            }
+            match report.extensions {
+                Some(ext) => {
+                    println!(
+                        "{} {}",
+                        label("Client Version:"),
+                        ext.client_version()
+                            .unwrap_or_else(|| "(not sent)".to_string())
+                    );
+                    let offered: Vec<String> = ext
+                        .m
+                        .iter()
+                        .map(|(name, id)| format!("{}={}", name, id))
+                        .collect();
+                    println!("{} {}", label("Extensions:"), offered.join(", "));
+                }
+                None if report.supports_extensions() => {
+                    println!("{} handshake not sent", label("Extensions:"))
                 }
+                None => {}
+            }
+            for anomaly in &report.anomalies {
+                println!("{} {}", label("Anomaly:"), anomaly);
+            }
+            Ok(())
+        }
+        "download_piece" | "magnet_download_piece" => {
+            if args.get(2).map(String::as_str) != Some("-o") {
+                return Err(Error::Usage(
+                    "output must be specified with -o <filepath> as 2nd & 3rd args".into(),
+                )
+                .into());
             }
 
-            eprintln!("indicating interest");
-            peer.indicate_interest().await?;
+            let outfile = arg(&args, 3, "an output path")?;
+            let _lock = utils::TargetLock::acquire(outfile)?;
+            let source = arg(&args, 4, "a torrent file or magnet link")?;
+            let piece_idx: u32 = arg(&args, 5, "a piece index")?
+                .parse()
+                .context("could not parse given piece index")?;
 
-            eprintln!("checking if peer is choking");
-            while peer.choking() {
-                let msgs = peer.poll().await?;
-                for m in msgs {
-                    eprintln!("waiting for unchoke, got: {:?}", m);
-                }
-            }
+            // --peer <ip:port> skips the tracker and reports on every block
+            let only_peer = flag_values(rest, "--peer")
+                .first()
+                .map(|a| SocketAddr::from_str(a))
+                .transpose()
+                .context("failed to parse --peer address")?;
+
+            let (metainf, mut peer) = if command.starts_with("magnet_") {
+                let link: magnet::MagnetLink = source.parse()?;
+                let peers = match only_peer {
+                    Some(addr) => vec![addr],
+                    None => download::magnet_peers(&link, peer_id, bind_addr).await?,
+                };
+                let (metainf, mut peer) =
+                    download::magnet_metainfo(&link, &peers, peer_id, dialer).await?;
+                download::wait_until_ready(&mut peer).await?;
+                (metainf, peer)
+            } else {
+                let metainf = metainfo::Metainfo::from_file(source)
+                    .await
+                    .context("failed to read metainfo file")?;
+                let peer = match only_peer {
+                    Some(addr) => download::ready_peer(addr, &metainf, peer_id, dialer).await?,
+                    None => download::unchoked_peer(&metainf, peer_id, bind_addr, dialer).await?,
+                };
+                (metainf, peer)
+            };
 
+            peer.set_pipeline_depth(pipeline_depth);
+            if only_peer.is_some() {
+                peer.record_block_timings();
+            }
             eprintln!("fetching piece");
-            let piece_buf = peer
-                .get_piece(
-                    piece_idx
-                        .parse()
-                        .context("could not parse given piece index")?,
-                )
-                .await?;
+            let started = std::time::Instant::now();
+            let fetched = peer.get_piece(piece_idx).await;
+            if only_peer.is_some() {
+                print_block_timings(&peer.take_block_timings(), started.elapsed());
+            }
+            let piece_buf = fetched.map_err(|e| {
+                Detailed::new(e, format!("failed fetching piece {}", piece_idx))
+                    .field("piece", piece_idx)
+            })?;
+            if !metainf.info.verify_piece(piece_idx, &piece_buf) {
+                anyhow::bail!("piece {} failed hash verification", piece_idx);
+            }
 
             let mut f = OpenOptions::new()
                 .write(true)
@@ -211,112 +544,513 @@ async fn main() -> anyhow::Result<()> {
 
             Ok(())
         }
-        "download" => {
-            assert_eq!(
-                args[2],
-                "-o".to_string(),
-                "output must be specified with -o <filepath> as 2nd & 3rd args"
-            );
-
-            let outfile = &args[3];
-            let mi_file = &args[4];
+        "download-range" => {
+            let mi_file = arg(&args, 2, "a torrent file")?;
+            let range = flag_values(rest, "--bytes");
+            let outfile = flag_values(rest, "-o");
+            let (Some(range), Some(outfile)) = (range.first(), outfile.first()) else {
+                return Err(Error::Usage(
+                    "usage: download-range <torrent> --bytes START..END -o <out>".into(),
+                )
+                .into());
+            };
+            let _lock = utils::TargetLock::acquire(outfile)?;
+            let (start, end) = range
+                .split_once("..")
+                .context("byte range must look like START..END")?;
+            let start: u64 = start.parse().context("could not parse range start")?;
+            let end: u64 = end.parse().context("could not parse range end")?;
 
-            let metainf = types::Metainfo::from_file(mi_file)
+            let metainf = metainfo::Metainfo::from_file(mi_file)
                 .await
                 .context("failed to read metainfo file")?;
+            if start >= end || end > metainf.info.length() {
+                anyhow::bail!(
+                    "byte range {}..{} is empty or past the end of the torrent ({} bytes)",
+                    start,
+                    end,
+                    metainf.info.length()
+                );
+            }
 
-            // tracker contact
+            let mut peer = download::unchoked_peer(&metainf, peer_id, bind_addr, dialer).await?;
+            peer.set_pipeline_depth(pipeline_depth);
+            let pieces =
+                download::download_range_to(&mut peer, &metainf, start, end, outfile).await?;
 
-            eprintln!("fetching peers from tracker at {}", metainf.announce);
-            let peers = tracker::announce(
-                &metainf.announce,
-                metainf.info.length(),
-                metainf.info.hash()?,
-                peer_id,
-            )
-            .await?;
-
-            // handshake begin
+            println!(
+                "Bytes {}..{} (pieces {:?}) downloaded to {}",
+                start, end, pieces, outfile
+            );
 
-            let mut peer = peer::PeerState::connect(peers[0], &metainf).await?;
-            eprintln!("waiting for handshake");
-            peer.wait_for_handshake().await?;
+            Ok(())
+        }
+        "extract" => {
+            let mi_file = arg(&args, 2, "a torrent file")?;
+            let wanted = flag_values(rest, "--file");
+            let outfile = flag_values(rest, "-o");
+            let (Some(wanted), Some(outfile)) = (wanted.first(), outfile.first()) else {
+                return Err(Error::Usage(
+                    "usage: extract <torrent> --file <index|path> -o <out>".into(),
+                )
+                .into());
+            };
+            let _lock = utils::TargetLock::acquire(outfile)?;
 
-            eprintln!("checking if i have peer's bitfield");
-            while peer.bitfield().is_empty() {
-                let msgs = peer.poll().await?;
-                if msgs.is_empty() {
-                    eprintln!("got nothing from peer this round");
-                } else {
-                    for m in msgs {
-                        eprintln!("waiting for bitfield, got: {:?}", m);
-                    }
-                }
+            let metainf = metainfo::Metainfo::from_file(mi_file)
+                .await
+                .context("failed to read metainfo file")?;
+            let files = metainf.info.file_spans();
+            let file = match wanted.parse::<usize>() {
+                Ok(idx) => files.get(idx),
+                Err(_) => files.iter().find(|f| f.path.join("/") == *wanted),
             }
+            .with_context(|| format!("torrent has no file {}", wanted))?;
 
-            eprintln!("indicating interest");
-            peer.indicate_interest().await?;
+            if file.length == 0 {
+                fs::File::create(outfile)
+                    .await
+                    .context("error creating empty output file")?;
+            } else {
+                let mut peer =
+                    download::unchoked_peer(&metainf, peer_id, bind_addr, dialer).await?;
+                peer.set_pipeline_depth(pipeline_depth);
+                download::download_range_to(
+                    &mut peer,
+                    &metainf,
+                    file.offset,
+                    file.offset + file.length,
+                    outfile,
+                )
+                .await?;
+            }
 
-            eprintln!("checking if peer is choking");
-            while peer.choking() {
-                let msgs = peer.poll().await?;
-                for m in msgs {
-                    eprintln!("waiting for unchoke, got: {:?}", m);
-                }
+            println!("File {} extracted to {}", file.path.join("/"), outfile);
+            Ok(())
+        }
+        "download" | "magnet_download" => {
+            if args.get(2).map(String::as_str) != Some("-o") {
+                return Err(Error::Usage(
+                    "output must be specified with -o <filepath> as 2nd & 3rd args".into(),
+                )
+                .into());
             }
 
-            let mut piece_files = vec![];
+            let outfile = arg(&args, 3, "an output path")?;
+            let _lock = utils::TargetLock::acquire(outfile)?;
+            let source = arg(&args, 4, "a torrent file or magnet link")?;
+
+            // multi-file torrents are laid out under `outfile` as a directory
+            let target = Path::new(outfile);
+            let (metainf, peers, reannouncer, saved) = if command.starts_with("magnet_") {
+                let link: magnet::MagnetLink = source.parse()?;
+                let peers = download::magnet_peers(&link, peer_id, bind_addr).await?;
+                let (metainf, _) =
+                    download::magnet_metainfo(&link, &peers, peer_id, dialer).await?;
+                let saved = download::resume::ResumeState::load(
+                    target,
+                    metainf.info_hash()?,
+                    metainf.info.num_pieces(),
+                )
+                .await?;
+                (metainf, peers, None, saved)
+            } else {
+                let metainf = metainfo::Metainfo::from_file(source)
+                    .await
+                    .context("failed to read metainfo file")?;
+                // loaded before announcing, since it holds the tracker ids
+                // an interrupted download was given
+                let saved = download::resume::ResumeState::load(
+                    target,
+                    metainf.info_hash()?,
+                    metainf.info.num_pieces(),
+                )
+                .await?;
+                let (peers, reannouncer) =
+                    download::announce_peers(&metainf, peer_id, bind_addr, saved.as_ref()).await?;
+                (metainf, peers, Some(reannouncer), saved)
+            };
 
-            for (piece_idx, piece_hash) in metainf.info.pieces().chunks(20).enumerate() {
-                let piece_idx = piece_idx as u32;
+            let info_hash = metainf.info_hash()?;
+            let num_pieces = metainf.info.num_pieces();
+            let (mut output, mut resume) = match saved {
+                Some(mut resume) => {
+                    let mut output = download::Storage::reopen(&metainf.info, target).await?;
+                    let kept = resume.verify(&metainf.info, &mut output).await?;
+                    eprintln!("resuming with {} of {} pieces done", kept, num_pieces);
+                    (output, resume)
+                }
+                None => (
+                    download::Storage::create(&metainf.info, target).await?,
+                    download::resume::ResumeState::new(target, info_hash, num_pieces),
+                ),
+            };
+            let verify_writes = rest.iter().any(|a| a == "--verify-writes");
+            let stats = download::download_all(
+                &metainf,
+                &peers,
+                peer_id,
+                dialer,
+                &mut output,
+                verify_writes,
+                reannouncer,
+                Box::new(download::picker::RarestFirst::new(num_pieces)),
+                endgame,
+                pipeline_depth,
+                &mut resume,
+            )
+            .await
+            .map_err(|e| {
+                Detailed::new(e, format!("failed downloading {}", source))
+                    .field("info_hash", hex::encode(info_hash))
+            })?;
+            if stats.corrupt_bytes > 0 {
                 eprintln!(
-                    "fetching piece {} with hash {}",
-                    piece_idx,
-                    hex::encode(piece_hash)
+                    "discarded {} bytes of pieces that failed hash verification",
+                    stats.corrupt_bytes
                 );
-                let piece_buf = peer.get_piece(piece_idx).await?;
+            }
+            if stats.redundant_bytes > 0 {
+                eprintln!(
+                    "discarded {} bytes of pieces another peer delivered first",
+                    stats.redundant_bytes
+                );
+            }
+            if verify_writes {
+                eprintln!(
+                    "spent {:.2?} re-reading pieces from disk to verify them",
+                    stats.verify_time
+                );
+            }
 
-                let piece_filename = outfile.to_string() + ".part" + &format!("{:03}", piece_idx);
-                let mut f = OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(&piece_filename)
-                    .await
-                    .context("error opening file for writing piece")?;
-                f.write_all(&piece_buf)
-                    .await
-                    .context("error writing out piece buffer to file")?;
+            println!("Downloaded {} to {}.", source, outfile);
 
-                eprintln!("Piece {} downloaded to {}", piece_idx, &piece_filename);
-                piece_files.push(piece_filename);
+            Ok(())
+        }
+        "conformance" => {
+            let traces = args.get(2..).unwrap_or_default();
+            if traces.is_empty() {
+                return Err(Error::Usage("usage: conformance <trace file>...".into()).into());
+            }
+            // each file holds the raw bytes one side of a connection sent
+            for trace in traces {
+                let stream = fs::read(trace)
+                    .await
+                    .with_context(|| format!("failed reading {}", trace))?;
+                let frames = peer::trace::replay(&stream)
+                    .with_context(|| format!("{} does not round-trip", trace))?;
+                for (offset, frame) in frames.iter() {
+                    println!("{}\t{}\t{}", trace, offset, frame);
+                }
+                eprintln!("{}: {} frames OK", trace, frames.len());
+            }
+            Ok(())
+        }
+        "verify" => {
+            let metainf = metainfo::Metainfo::from_file(arg(&args, 2, "a torrent file")?)
+                .await
+                .context("failed to read metainfo file")?;
+            // a file for single-file torrents, the directory holding them otherwise
+            let target = arg(&args, 3, "the data to check")?;
+            let mut storage = download::Storage::open(&metainf.info, Path::new(target)).await?;
+            let good = download::check_pieces(&metainf.info, &mut storage).await?;
+            for problem in storage.problems() {
+                println!("Problem: {}", problem);
             }
 
-            let mut output = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(outfile)
+            let (ok, bad): (Vec<usize>, Vec<usize>) = (0..good.len()).partition(|i| good[*i]);
+            println!("Good pieces: {}", piece_ranges(&ok));
+            println!("Bad pieces: {}", piece_ranges(&bad));
+            println!(
+                "{} of {} pieces OK ({:.1}% complete)",
+                ok.len(),
+                good.len(),
+                if good.is_empty() {
+                    100.0
+                } else {
+                    ok.len() as f64 * 100.0 / good.len() as f64
+                }
+            );
+            if !bad.is_empty() {
+                anyhow::bail!("{} pieces failed verification", bad.len());
+            }
+            if !storage.problems().is_empty() {
+                anyhow::bail!(
+                    "every piece is good, but {} files don't match the torrent",
+                    storage.problems().len()
+                );
+            }
+            Ok(())
+        }
+        "seed" => {
+            let metainf = metainfo::Metainfo::from_file(arg(&args, 2, "a torrent file")?)
                 .await
-                .context("error opening out file")?;
-            for pf in piece_files {
-                let mut input = OpenOptions::new()
-                    .write(false)
-                    .read(true)
-                    .open(&pf)
-                    .await
-                    .context("error opening piece file for reading")?;
-                tokio::io::copy(&mut input, &mut output).await?;
-                drop(input);
-                fs::remove_file(&pf).await?;
+                .context("failed to read metainfo file")?;
+            // the same path that was given to download, file or directory
+            let target = arg(&args, 3, "the downloaded data")?;
+            let storage = download::Storage::open(&metainf.info, Path::new(target)).await?;
+            for problem in storage.problems() {
+                eprintln!("warning: {}", problem);
             }
-            eprintln!(
-                "copied pieces into outfile {} and removed piece files",
+            let uploaded = seed::seed(&metainf, storage, peer_id, bind_addr).await?;
+            println!("Stopped seeding after uploading {} bytes.", uploaded);
+            Ok(())
+        }
+        "create" => {
+            let source = arg(&args, 2, "a file or directory")?;
+            // --dry-run shows what would be created, before any hashing
+            let dry_run = rest.iter().any(|a| a == "--dry-run");
+            let announce = flag_values(rest, "--announce");
+            let outfile = flag_values(rest, "-o");
+            let (Some(announce), Some(outfile)) = (
+                announce.first(),
                 outfile
-            );
+                    .first()
+                    .map(String::as_str)
+                    .or(dry_run.then_some("")),
+            ) else {
+                return Err(Error::Usage(
+                    "usage: create <path> --announce <url> [--piece-length <n>] -o <out.torrent> \
+                     [--creation-date <unix time|now>] [--dry-run]"
+                        .into(),
+                )
+                .into());
+            };
+            let plan = metainfo::CreatePlan::new(Path::new(source)).await?;
+            let (piece_length, recommended) = match flag_values(rest, "--piece-length").first() {
+                Some(n) => (n.parse().context("failed to parse --piece-length")?, false),
+                None => (metainfo::recommended_piece_length(plan.total_size()), true),
+            };
+            // left out unless asked for, so the same data gives the same bytes
+            let creation_date = match flag_values(rest, "--creation-date").first() {
+                None => None,
+                Some(now) if now == "now" => Some(
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .context("system clock is before 1970")?
+                        .as_secs() as i64,
+                ),
+                Some(date) => Some(
+                    date.parse::<i64>()
+                        .context("failed to parse --creation-date as a unix time")?,
+                ),
+            };
 
+            if dry_run {
+                let mut skeleton = plan.skeleton(announce, piece_length)?;
+                skeleton.creation_date = creation_date;
+                let label = |l: &str| utils::paint(l, utils::BOLD, color);
+                println!("{}", label("Files:"));
+                for (_, path, size) in &plan.files {
+                    let path = if plan.is_dir {
+                        path.join("/")
+                    } else {
+                        plan.name.clone()
+                    };
+                    println!("{}\t{}", size, path);
+                }
+                println!("{} {}", label("Total Size:"), plan.total_size());
+                println!(
+                    "{} {}{}",
+                    label("Piece Length:"),
+                    piece_length,
+                    if recommended { " (recommended)" } else { "" }
+                );
+                println!("{} {}", label("Pieces:"), plan.num_pieces(piece_length));
+                // only the hashes are missing, and they're a fixed size
+                println!(
+                    "{} {} bytes",
+                    label("Torrent Size:"),
+                    serde_bencode::to_bytes(&skeleton)?.len()
+                );
+                for warning in skeleton.warnings() {
+                    eprintln!("warning: {}", warning);
+                }
+                return Ok(());
+            }
+
+            let mut metainf =
+                metainfo::Metainfo::create(Path::new(source), announce, piece_length).await?;
+            metainf.creation_date = creation_date;
+            for warning in metainf.warnings() {
+                eprintln!("warning: {}", warning);
+            }
+            fs::write(outfile, serde_bencode::to_bytes(&metainf)?)
+                .await
+                .with_context(|| format!("error writing {}", outfile))?;
+
+            println!(
+                "Created {} ({} pieces, info hash {})",
+                outfile,
+                metainf.info.num_pieces(),
+                hex::encode(metainf.info_hash()?)
+            );
             Ok(())
         }
-        _ => {
-            anyhow::bail!("unknown command: {}", args[1])
+        _ => Err(Error::Usage(format!("unknown command: {}", command)).into()),
+    }
+}
+
+fn print_info(metainf: &metainfo::Metainfo, color: bool) -> anyhow::Result<()> {
+    let label = |l: &str| utils::paint(l, utils::BOLD, color);
+    println!("{} {}", label("Tracker URL:"), metainf.announce);
+    println!("{} {}", label("Length:"), metainf.info.length());
+    println!(
+        "{} {}",
+        label("Info Hash:"),
+        hex::encode(metainf.info_hash()?)
+    );
+    println!("{} {}", label("Piece Length:"), metainf.info.piece_length());
+    println!("{}", label("Piece Hashes:"));
+    for ph in metainf.info.pieces().chunks(20).map(Vec::from) {
+        println!("{}", hex::encode(ph));
+    }
+    if let Some(date) = metainf.creation_date {
+        println!("{} {} (unix time)", label("Creation Date:"), date);
+    }
+    if let Some(comment) = &metainf.comment {
+        println!("{} {}", label("Comment:"), comment);
+    }
+    if let Some(created_by) = &metainf.created_by {
+        println!("{} {}", label("Created By:"), created_by);
+    }
+    if let Some(encoding) = &metainf.encoding {
+        println!("{} {}", label("Encoding:"), encoding);
+    }
+    if metainf.info.private_flag().is_some() {
+        println!(
+            "{} {}",
+            label("Private:"),
+            if metainf.info.private() { "yes" } else { "no" }
+        );
+    }
+    if let metainfo::InfoDict::MultiFile { .. } = metainf.info {
+        println!("{}", label("Files:"));
+        for f in metainf.info.file_spans() {
+            println!("{}\t{}", f.length, f.path.join("/"));
         }
     }
+    Ok(())
+}
+
+// sorted piece indices as compact ranges, e.g. "0-4, 7, 9-10"
+fn piece_ranges(indices: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for &i in indices {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == i => *end = i,
+            _ => ranges.push((i, i)),
+        }
+    }
+    if ranges.is_empty() {
+        return "none".to_string();
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// one line per block to stderr, flagging blocks that weren't what we asked for
+fn print_block_timings(timings: &[peer::BlockTiming], total: Duration) {
+    for t in timings {
+        let anomaly = if t.received != t.requested {
+            format!("  <- asked for {} bytes", t.requested)
+        } else {
+            String::new()
+        };
+        eprintln!(
+            "piece {} block at {:>8}: {:>6} bytes in {:>5}ms{}",
+            t.index,
+            t.begin,
+            t.received,
+            t.elapsed.as_millis(),
+            anomaly
+        );
+    }
+    eprintln!("{} blocks in {}ms", timings.len(), total.as_millis());
+}
+
+// the positional argument at `idx`, or a usage error naming what was expected there
+fn arg<'a>(args: &'a [String], idx: usize, what: &str) -> Result<&'a str, Error> {
+    args.get(idx)
+        .map(String::as_str)
+        .ok_or_else(|| Error::Usage(format!("expected {} as argument {}", what, idx)))
+}
+
+/// Collects the value following every occurrence of `flag` in `args`, e.g.
+/// `--tracker a --tracker b` yields `["a", "b"]`.
+fn flag_values(args: &[String], flag: &str) -> Vec<String> {
+    args.iter()
+        .zip(args.iter().skip(1))
+        .filter(|(a, _)| *a == flag)
+        .map(|(_, v)| v.clone())
+        .collect()
+}
+
+// TCP-pings and handshakes every peer concurrently, then prints one row each
+async fn print_peer_table(
+    peers: &[(SocketAddr, String)],
+    show_tracker: bool,
+    color: bool,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    dialer: peer::Dialer,
+) {
+    let probes: Vec<_> = peers
+        .iter()
+        .map(|(addr, _)| {
+            tokio::spawn(peer::probe(
+                *addr,
+                info_hash,
+                peer_id,
+                dialer,
+                Duration::from_secs(PROBE_TIMEOUT_SECS),
+            ))
+        })
+        .collect();
+
+    let header = format!(
+        "{:<47} {:<9} {:>7} {:<24}{}",
+        "ADDRESS",
+        "REACHABLE",
+        "RTT",
+        "CLIENT",
+        if show_tracker { " TRACKER" } else { "" }
+    );
+    println!("{}", utils::paint(&header, utils::BOLD, color));
+    for ((addr, tracker_addr), probe) in peers.iter().zip(probes) {
+        let probe = probe.await.ok();
+        let rtt = probe.as_ref().and_then(|p| p.rtt);
+        let client = probe
+            .as_ref()
+            .and_then(|p| p.peer_id)
+            .map(|id| peer::client_name(&id).unwrap_or_else(|| "(unknown)".to_string()))
+            .unwrap_or_else(|| "-".to_string());
+        // pad before painting so escape codes don't throw off the columns
+        let reachable = match rtt {
+            Some(_) => utils::paint(&format!("{:<9}", "yes"), utils::GREEN, color),
+            None => utils::paint(&format!("{:<9}", "no"), utils::RED, color),
+        };
+        println!(
+            "{:<47} {} {:>7} {:<24}{}",
+            addr.to_string(),
+            reachable,
+            rtt.map(|d| format!("{}ms", d.as_millis()))
+                .unwrap_or_else(|| "-".to_string()),
+            client,
+            if show_tracker {
+                format!(" {}", tracker_addr)
+            } else {
+                String::new()
+            }
+        );
+    }
 }
@@ -0,0 +1,874 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use crate::bencode::{self, KeyPolicy};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::{fs::File, io::AsyncReadExt};
+
+// whole pieces are held in memory while downloading, so refuse anything bigger
+pub const MAX_PIECE_LENGTH: u32 = 128 * 1024 * 1024;
+// a piece smaller than one request block is legal but unusual
+pub const MIN_USUAL_PIECE_LENGTH: u32 = 16 * 1024;
+// `create` picks piece lengths that give about this many pieces, but none
+// bigger than MAX_RECOMMENDED_PIECE_LENGTH
+const TARGET_PIECES: u64 = 1500;
+const MAX_RECOMMENDED_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InfoDictFile {
+    pub length: u64,
+    pub path: Vec<String>,
+}
+
+// where one file sits within the torrent's concatenated data
+pub struct FileSpan {
+    pub path: Vec<String>,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum InfoDict {
+    SingleFile {
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        pieces: ByteBuf,
+        length: u64,
+        // BEP 27: 1 means peers only come from the torrent's trackers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        private: Option<i64>,
+    },
+    MultiFile {
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        pieces: ByteBuf,
+        files: Vec<InfoDictFile>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        private: Option<i64>,
+    },
+}
+
+impl InfoDict {
+    // decodes an info dict as peers send it for a magnet link
+    pub fn from_bytes(buf: &[u8]) -> anyhow::Result<Self> {
+        check_lengths(&bencode::decode(buf)?)?;
+        Ok(bencode::decode(buf)?)
+    }
+
+    pub fn hash(&self) -> anyhow::Result<[u8; 20]> {
+        let mut hasher = Sha1::new();
+        hasher.update(serde_bencode::to_bytes(&self)?);
+        Ok(hasher.finalize().into())
+    }
+
+    pub fn piece_length(&self) -> u32 {
+        match &self {
+            InfoDict::SingleFile { piece_length, .. } => *piece_length,
+            InfoDict::MultiFile { piece_length, .. } => *piece_length,
+        }
+    }
+
+    pub fn pieces(&self) -> &ByteBuf {
+        match &self {
+            InfoDict::SingleFile { pieces, .. } => pieces,
+            InfoDict::MultiFile { pieces, .. } => pieces,
+        }
+    }
+
+    pub fn private(&self) -> bool {
+        self.private_flag() == Some(1)
+    }
+
+    // the "private" key as given, if the info dict has one at all
+    pub fn private_flag(&self) -> Option<i64> {
+        match &self {
+            InfoDict::SingleFile { private, .. } => *private,
+            InfoDict::MultiFile { private, .. } => *private,
+        }
+    }
+
+    pub fn file_spans(&self) -> Vec<FileSpan> {
+        match &self {
+            InfoDict::SingleFile { name, length, .. } => vec![FileSpan {
+                path: vec![name.clone()],
+                offset: 0,
+                length: *length,
+            }],
+            InfoDict::MultiFile { files, .. } => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .map(|f| {
+                        let span = FileSpan {
+                            path: f.path.clone(),
+                            offset,
+                            length: f.length,
+                        };
+                        offset += f.length;
+                        span
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces().len() / 20
+    }
+
+    pub fn piece_hash(&self, index: u32) -> Option<&[u8]> {
+        self.pieces().chunks(20).nth(index as usize)
+    }
+
+    // every piece is piece_length long except possibly the last; pieces past
+    // the end have no size at all
+    pub fn piece_size(&self, index: u32) -> u32 {
+        let start = u64::from(self.piece_length()) * u64::from(index);
+        self.length()
+            .checked_sub(start)
+            .map_or(0, |rest| rest.min(self.piece_length().into()) as u32)
+    }
+
+    pub fn verify_piece(&self, index: u32, data: &[u8]) -> bool {
+        self.piece_hash(index)
+            .map_or(false, |expected| Sha1::digest(data).as_slice() == expected)
+    }
+
+    // saturates rather than overflowing; validate() rejects totals that would
+    pub fn length(&self) -> u64 {
+        match &self {
+            InfoDict::SingleFile { length, .. } => *length,
+            InfoDict::MultiFile { files, .. } => files
+                .iter()
+                .fold(0u64, |total, f| total.saturating_add(f.length)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Metainfo {
+    pub announce: String,
+    pub info: InfoDict,
+    #[serde(rename = "announce-list")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub announce_list: Vec<Vec<String>>,
+    // seconds since the unix epoch
+    #[serde(rename = "creation date")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creation_date: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(rename = "created by")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    // the character encoding of the strings in the metainfo, e.g. UTF-8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    // the info dict exactly as it was encoded, which is what the info hash
+    // covers; re-encoding `info` would drop keys it doesn't model
+    #[serde(skip)]
+    pub info_bytes: Option<Vec<u8>>,
+}
+
+// what `create` would hash, found from file sizes without reading any data
+pub struct CreatePlan {
+    pub name: String,
+    pub is_dir: bool,
+    // each file's path on disk, its path within the torrent, and its size
+    pub files: Vec<(PathBuf, Vec<String>, u64)>,
+}
+
+impl CreatePlan {
+    pub async fn new(path: &Path) -> anyhow::Result<Self> {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .with_context(|| format!("{} has no usable file name", path.display()))?
+            .to_string();
+
+        let is_dir = tokio::fs::metadata(path)
+            .await
+            .with_context(|| format!("error reading {}", path.display()))?
+            .is_dir();
+        let listed = if is_dir {
+            list_files(path).await?
+        } else {
+            vec![(path.to_path_buf(), vec![])]
+        };
+        if listed.is_empty() {
+            bail!("{} has no files in it", path.display());
+        }
+        let mut files = Vec::with_capacity(listed.len());
+        for (file_path, torrent_path) in listed {
+            let size = tokio::fs::metadata(&file_path)
+                .await
+                .with_context(|| format!("error reading {}", file_path.display()))?
+                .len();
+            files.push((file_path, torrent_path, size));
+        }
+        Ok(CreatePlan {
+            name,
+            is_dir,
+            files,
+        })
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|(_, _, size)| size).sum()
+    }
+
+    pub fn num_pieces(&self, piece_length: u32) -> u64 {
+        self.total_size().div_ceil(piece_length.into())
+    }
+
+    // the metainfo `create` would make, except that every piece hash is zero
+    pub fn skeleton(&self, announce: &str, piece_length: u32) -> anyhow::Result<Metainfo> {
+        check_create_piece_length(piece_length)?;
+        let files = self
+            .files
+            .iter()
+            .map(|(_, torrent_path, size)| InfoDictFile {
+                length: *size,
+                path: torrent_path.clone(),
+            })
+            .collect();
+        let pieces = vec![0; self.num_pieces(piece_length) as usize * 20];
+        Ok(Metainfo::created(
+            self.name.clone(),
+            self.is_dir,
+            announce,
+            piece_length,
+            pieces,
+            files,
+        ))
+    }
+}
+
+// a power of two giving about TARGET_PIECES pieces, within the usual bounds
+pub fn recommended_piece_length(total_size: u64) -> u32 {
+    (total_size / TARGET_PIECES).next_power_of_two().clamp(
+        MIN_USUAL_PIECE_LENGTH.into(),
+        MAX_RECOMMENDED_PIECE_LENGTH.into(),
+    ) as u32
+}
+
+fn check_create_piece_length(piece_length: u32) -> anyhow::Result<()> {
+    if piece_length == 0 || piece_length > MAX_PIECE_LENGTH {
+        bail!(
+            "piece length must be between 1 and {}, not {}",
+            MAX_PIECE_LENGTH,
+            piece_length
+        );
+    }
+    Ok(())
+}
+
+impl Metainfo {
+    // metainfo with nothing but the info dict, as when all we have is what
+    // peers sent for a magnet link
+    pub fn from_info(info: InfoDict, info_bytes: Vec<u8>) -> Self {
+        Metainfo {
+            announce: String::new(),
+            info,
+            announce_list: vec![],
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+            info_bytes: Some(info_bytes),
+        }
+    }
+
+    // where an info dict fetched for a magnet link is kept, so it can be
+    // looked up again by info hash without asking peers
+    pub fn cache_path(info_hash: [u8; 20]) -> Option<PathBuf> {
+        let base = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+        Some(
+            base.join("bittorrent-starter-rust")
+                .join(format!("{}.info", hex::encode(info_hash))),
+        )
+    }
+
+    // saves the raw info dict to the metadata cache, if we have it
+    pub async fn cache(&self) -> anyhow::Result<()> {
+        let (Some(raw), Some(path)) = (&self.info_bytes, Self::cache_path(self.info_hash()?))
+        else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&path, raw)
+            .await
+            .with_context(|| format!("failed writing {}", path.display()))
+    }
+
+    pub async fn from_cache(info_hash: [u8; 20]) -> anyhow::Result<Self> {
+        let path = Self::cache_path(info_hash).context("no cache directory (HOME is not set)")?;
+        if !path.exists() {
+            bail!(
+                "no cached metadata for {}; fetch it once with magnet_info",
+                hex::encode(info_hash)
+            );
+        }
+        Self::from_file(path).await
+    }
+
+    // hashes the file or directory at `path` into a new torrent. files in a
+    // directory are taken in sorted path order and no creation date is set,
+    // so the same data and announce URL always give the same .torrent bytes.
+    pub async fn create(path: &Path, announce: &str, piece_length: u32) -> anyhow::Result<Self> {
+        check_create_piece_length(piece_length)?;
+        let plan = CreatePlan::new(path).await?;
+
+        let mut pieces = vec![];
+        let mut piece = Vec::with_capacity(piece_length as usize);
+        let mut info_files = vec![];
+        for (file_path, torrent_path, _) in plan.files {
+            let mut file = File::open(&file_path)
+                .await
+                .with_context(|| format!("error opening {}", file_path.display()))?;
+            let mut length: u64 = 0;
+            loop {
+                let want = piece_length as usize - piece.len();
+                let start = piece.len();
+                piece.resize(start + want, 0);
+                let n = file
+                    .read(&mut piece[start..])
+                    .await
+                    .with_context(|| format!("error reading {}", file_path.display()))?;
+                piece.truncate(start + n);
+                if n == 0 {
+                    break;
+                }
+                length += n as u64;
+                if piece.len() == piece_length as usize {
+                    pieces.extend_from_slice(&Sha1::digest(&piece));
+                    piece.clear();
+                }
+            }
+            info_files.push(InfoDictFile {
+                length,
+                path: torrent_path,
+            });
+        }
+        if !piece.is_empty() {
+            pieces.extend_from_slice(&Sha1::digest(&piece));
+        }
+
+        let metainfo = Self::created(
+            plan.name,
+            plan.is_dir,
+            announce,
+            piece_length,
+            pieces,
+            info_files,
+        );
+        metainfo.validate()?;
+        Ok(metainfo)
+    }
+
+    fn created(
+        name: String,
+        is_dir: bool,
+        announce: &str,
+        piece_length: u32,
+        pieces: Vec<u8>,
+        files: Vec<InfoDictFile>,
+    ) -> Self {
+        let info = if is_dir {
+            InfoDict::MultiFile {
+                name,
+                piece_length,
+                pieces: ByteBuf::from(pieces),
+                files,
+                private: None,
+            }
+        } else {
+            InfoDict::SingleFile {
+                name,
+                piece_length,
+                pieces: ByteBuf::from(pieces),
+                length: files.iter().map(|f| f.length).sum(),
+                private: None,
+            }
+        };
+        Metainfo {
+            announce: announce.to_string(),
+            info,
+            announce_list: vec![],
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+            info_bytes: None,
+        }
+    }
+
+    pub async fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::from_file_with_policy(path, KeyPolicy::KeepLast).await
+    }
+
+    pub async fn from_file_with_policy<P: AsRef<Path>>(
+        path: P,
+        policy: KeyPolicy,
+    ) -> anyhow::Result<Self> {
+        let mut file = File::open(path).await?;
+        let fsz = file.metadata().await?.len();
+        let mut contents = Vec::with_capacity(fsz.try_into()?);
+        file.read_to_end(&mut contents).await?;
+        let info_bytes =
+            bencode::raw_value(&contents, b"info", policy)?.map(|range| contents[range].to_vec());
+        // a bare info dict, e.g. saved from a magnet link, has no "info" key of
+        // its own but does have the keys inside one
+        let bare_info = info_bytes.is_none()
+            && bencode::raw_value(&contents, b"piece length", policy)?.is_some();
+        let (normalized, fixes) = bencode::normalize(&contents, policy)?;
+        for fix in fixes {
+            eprintln!("warning: metainfo has {}", fix);
+        }
+        let value: Value = serde_bencode::from_bytes(&normalized)?;
+        match &value {
+            _ if bare_info => check_lengths(&value)?,
+            Value::Dict(dict) => {
+                if let Some(info) = dict.get(&b"info"[..]) {
+                    check_lengths(info)?;
+                }
+            }
+            _ => {}
+        }
+        let metainfo = if bare_info {
+            let info: InfoDict =
+                serde_bencode::from_bytes(&normalized).context("failed decoding info dict")?;
+            Self::from_info(info, contents)
+        } else {
+            let mut metainfo: Self =
+                serde_bencode::from_bytes(&normalized).context("failed decoding metainfo")?;
+            metainfo.info_bytes = info_bytes;
+            metainfo
+        };
+        metainfo.validate()?;
+        for warning in metainfo.warnings() {
+            eprintln!("warning: {}", warning);
+        }
+        Ok(metainfo)
+    }
+
+    pub fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
+        match &self.info_bytes {
+            Some(raw) => Ok(Sha1::digest(raw.as_slice()).into()),
+            None => self.info.hash(),
+        }
+    }
+
+    // catches values that would otherwise blow up arithmetic later on
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let info = &self.info;
+        if info.piece_length() == 0 {
+            bail!("info.piece length must be greater than zero");
+        }
+        if info.piece_length() > MAX_PIECE_LENGTH {
+            bail!(
+                "info.piece length {} is larger than the supported maximum of {}",
+                info.piece_length(),
+                MAX_PIECE_LENGTH
+            );
+        }
+        if info.pieces().len() % 20 != 0 {
+            bail!(
+                "info.pieces is {} bytes, which is not a whole number of SHA-1 hashes",
+                info.pieces().len()
+            );
+        }
+        if let InfoDict::MultiFile { files, .. } = info {
+            if files.is_empty() {
+                bail!("info.files is empty");
+            }
+            if files
+                .iter()
+                .try_fold(0u64, |total, f| total.checked_add(f.length))
+                .is_none()
+            {
+                bail!("info.files total length does not fit in 64 bits");
+            }
+        }
+        let needed = info.length().div_ceil(info.piece_length().into());
+        if needed > u32::MAX as u64 + 1 {
+            bail!(
+                "info.length needs {} pieces, more than a 32-bit piece index can count",
+                needed
+            );
+        }
+        if needed as usize != info.num_pieces() {
+            bail!(
+                "info.pieces has {} hashes but info.length needs {}",
+                info.num_pieces(),
+                needed
+            );
+        }
+        Ok(())
+    }
+
+    // legal but unusual choices that some clients handle poorly
+    pub fn warnings(&self) -> Vec<String> {
+        let piece_length = self.info.piece_length();
+        let mut warnings = vec![];
+        if !piece_length.is_power_of_two() {
+            warnings.push(format!(
+                "info.piece length {} is not a power of two",
+                piece_length
+            ));
+        }
+        if piece_length < MIN_USUAL_PIECE_LENGTH {
+            warnings.push(format!(
+                "info.piece length {} is smaller than {}",
+                piece_length, MIN_USUAL_PIECE_LENGTH
+            ));
+        }
+        warnings
+    }
+}
+
+// serde's error for a length that doesn't fit a u64 names neither the key nor
+// the value, so negative lengths are looked for in the untyped info dict first
+fn check_lengths(info: &Value) -> anyhow::Result<()> {
+    let Value::Dict(info) = info else {
+        return Ok(());
+    };
+    let mut lengths = vec![("info.length".to_string(), info.get(&b"length"[..]))];
+    if let Some(Value::List(files)) = info.get(&b"files"[..]) {
+        for (idx, file) in files.iter().enumerate() {
+            if let Value::Dict(file) = file {
+                lengths.push((
+                    format!("info.files[{}].length", idx),
+                    file.get(&b"length"[..]),
+                ));
+            }
+        }
+    }
+    for (key, length) in lengths {
+        if let Some(Value::Int(n)) = length {
+            if *n < 0 {
+                bail!("{} is negative ({})", key, n);
+            }
+        }
+    }
+    Ok(())
+}
+
+// every regular file under `dir`, paired with its path components relative to
+// `dir`, sorted so the order doesn't depend on the filesystem
+async fn list_files(dir: &Path) -> anyhow::Result<Vec<(PathBuf, Vec<String>)>> {
+    let mut files = vec![];
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(next) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&next)
+            .await
+            .with_context(|| format!("error listing {}", next.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if tokio::fs::metadata(&path).await?.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let components = path
+                .strip_prefix(dir)?
+                .iter()
+                .map(|c| {
+                    c.to_str()
+                        .map(str::to_string)
+                        .with_context(|| format!("{} is not valid UTF-8", path.display()))
+                })
+                .collect::<anyhow::Result<Vec<String>>>()?;
+            files.push((path, components));
+        }
+    }
+    files.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single(piece_length: u32, hashes: usize, length: u64) -> Metainfo {
+        let info = InfoDict::SingleFile {
+            name: "test".to_string(),
+            piece_length,
+            pieces: ByteBuf::from(vec![0; hashes * 20]),
+            length,
+            private: None,
+        };
+        Metainfo::from_info(info, vec![])
+    }
+
+    fn multi(files: &[u64]) -> Metainfo {
+        let info = InfoDict::MultiFile {
+            name: "test".to_string(),
+            piece_length: MAX_PIECE_LENGTH,
+            pieces: ByteBuf::from(vec![0; 20]),
+            files: files
+                .iter()
+                .enumerate()
+                .map(|(i, length)| InfoDictFile {
+                    length: *length,
+                    path: vec![i.to_string()],
+                })
+                .collect(),
+            private: None,
+        };
+        Metainfo::from_info(info, vec![])
+    }
+
+    fn rejection(metainfo: Metainfo) -> String {
+        metainfo.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn accepts_consistent_metainfo() {
+        single(16384, 3, 16384 * 2 + 1).validate().unwrap();
+        single(16384, 1, 16384).validate().unwrap();
+        multi(&[10, 20]).validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_piece_length() {
+        assert!(rejection(single(0, 1, 1)).contains("greater than zero"));
+        assert!(rejection(single(MAX_PIECE_LENGTH + 1, 1, 1)).contains("supported maximum"));
+    }
+
+    #[test]
+    fn rejects_partial_hashes() {
+        let mut metainfo = single(16384, 1, 16384);
+        if let InfoDict::SingleFile { pieces, .. } = &mut metainfo.info {
+            pieces.push(0);
+        }
+        assert!(rejection(metainfo).contains("whole number of SHA-1 hashes"));
+    }
+
+    #[test]
+    fn rejects_wrong_hash_count() {
+        assert!(rejection(single(16384, 2, 16384)).contains("has 2 hashes but info.length needs 1"));
+        assert!(rejection(single(16384, 1, 16385)).contains("has 1 hashes but info.length needs 2"));
+    }
+
+    #[test]
+    fn handles_torrents_over_4_gib() {
+        let piece_length = 4 * 1024 * 1024;
+        let metainfo = single(piece_length, 1280, 5 << 30);
+        metainfo.validate().unwrap();
+        assert_eq!(metainfo.info.piece_size(1279), piece_length);
+        assert_eq!(metainfo.info.piece_size(1280), 0);
+        assert_eq!(multi(&[1, 2]).info.piece_size(7), 0);
+    }
+
+    #[tokio::test]
+    async fn names_negative_lengths() {
+        let dir = tempfile::tempdir().unwrap();
+        for (info, key) in [
+            (&b"d6:lengthi-5e4:name1:a12:piece lengthi4e6:pieces0:e"[..], "info.length is negative (-5)"),
+            (
+                b"d5:filesld6:lengthi1e4:pathl1:aeed6:lengthi-2e4:pathl1:beee4:name1:a12:piece lengthi4e6:pieces0:e",
+                "info.files[1].length is negative (-2)",
+            ),
+        ] {
+            let err = InfoDict::from_bytes(info).err().unwrap();
+            assert_eq!(err.to_string(), key);
+
+            let path = dir.path().join("negative.torrent");
+            let torrent = [&b"d8:announce0:4:info"[..], info, b"e"].concat();
+            tokio::fs::write(&path, torrent).await.unwrap();
+            let err = Metainfo::from_file(&path).await.err().unwrap();
+            assert_eq!(err.to_string(), key);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_file_lists() {
+        assert!(rejection(multi(&[])).contains("info.files is empty"));
+        assert!(rejection(multi(&[u64::MAX, 1])).contains("does not fit in 64 bits"));
+    }
+
+    // what the create command writes, read back the way any other torrent is
+    async fn create_and_reparse(path: &Path, piece_length: u32) -> Metainfo {
+        let created = Metainfo::create(path, "http://tracker.example/announce", piece_length)
+            .await
+            .unwrap();
+        let file = path.with_extension("torrent");
+        tokio::fs::write(&file, serde_bencode::to_bytes(&created).unwrap())
+            .await
+            .unwrap();
+        let parsed = Metainfo::from_file(&file).await.unwrap();
+        assert_eq!(parsed.info_hash().unwrap(), created.info_hash().unwrap());
+        assert_eq!(parsed.announce, "http://tracker.example/announce");
+        parsed
+    }
+
+    fn pieces_match(info: &InfoDict, data: &[u8]) {
+        let chunks: Vec<&[u8]> = data.chunks(info.piece_length() as usize).collect();
+        assert_eq!(info.num_pieces(), chunks.len());
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            assert!(info.verify_piece(index as u32, chunk), "piece {}", index);
+        }
+    }
+
+    #[tokio::test]
+    async fn creates_single_file_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&path, &data).await.unwrap();
+
+        let parsed = create_and_reparse(&path, 16384).await;
+        let InfoDict::SingleFile { name, length, .. } = &parsed.info else {
+            panic!("expected a single-file torrent");
+        };
+        assert_eq!(name, "data.bin");
+        assert_eq!(*length, 40000);
+        pieces_match(&parsed.info, &data);
+    }
+
+    #[tokio::test]
+    async fn creates_multi_file_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("album");
+        tokio::fs::create_dir_all(root.join("disc 2"))
+            .await
+            .unwrap();
+        for (name, contents) in [
+            ("b.txt", &b"second"[..]),
+            ("disc 2/a.txt", b"third file"),
+            ("a.txt", b"first"),
+        ] {
+            tokio::fs::write(root.join(name), contents).await.unwrap();
+        }
+
+        let parsed = create_and_reparse(&root, 4).await;
+        let InfoDict::MultiFile { name, files, .. } = &parsed.info else {
+            panic!("expected a multi-file torrent");
+        };
+        assert_eq!(name, "album");
+        let listed: Vec<(String, u64)> =
+            files.iter().map(|f| (f.path.join("/"), f.length)).collect();
+        assert_eq!(
+            listed,
+            [
+                ("a.txt".to_string(), 5),
+                ("b.txt".to_string(), 6),
+                ("disc 2/a.txt".to_string(), 10),
+            ]
+        );
+        // pieces run across file boundaries
+        pieces_match(&parsed.info, b"firstsecondthird file");
+    }
+
+    #[tokio::test]
+    async fn same_data_same_info_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("set");
+        tokio::fs::create_dir_all(&root).await.unwrap();
+        for n in 0..5 {
+            tokio::fs::write(root.join(format!("{}.dat", n)), vec![n; 1000])
+                .await
+                .unwrap();
+        }
+        let first = create_and_reparse(&root, 1024).await.info_hash().unwrap();
+        for _ in 0..3 {
+            let again = Metainfo::create(&root, "http://other.example/announce", 1024)
+                .await
+                .unwrap();
+            assert_eq!(again.info_hash().unwrap(), first);
+        }
+    }
+
+    #[test]
+    fn recommends_power_of_two_piece_lengths() {
+        assert_eq!(recommended_piece_length(0), 16 * 1024);
+        assert_eq!(recommended_piece_length(10 * 1024 * 1024), 16 * 1024);
+        assert_eq!(recommended_piece_length(700 * 1024 * 1024), 512 * 1024);
+        assert_eq!(recommended_piece_length(4 << 30), 4 * 1024 * 1024);
+        assert_eq!(recommended_piece_length(1 << 50), 16 * 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn dry_run_plan_matches_what_create_makes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("set");
+        tokio::fs::create_dir_all(root.join("sub")).await.unwrap();
+        for (name, size) in [("b", 5000), ("sub/a", 70000), ("c", 0)] {
+            tokio::fs::write(root.join(name), vec![7; size])
+                .await
+                .unwrap();
+        }
+        let plan = CreatePlan::new(&root).await.unwrap();
+        let listed: Vec<(String, u64)> = plan
+            .files
+            .iter()
+            .map(|(_, path, size)| (path.join("/"), *size))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("b".to_string(), 5000),
+                ("c".to_string(), 0),
+                ("sub/a".to_string(), 70000)
+            ]
+        );
+        assert_eq!(plan.total_size(), 75000);
+        assert_eq!(plan.num_pieces(16384), 5);
+
+        let announce = "http://tracker.example/announce";
+        let skeleton = plan.skeleton(announce, 16384).unwrap();
+        let created = Metainfo::create(&root, announce, 16384).await.unwrap();
+        assert_eq!(
+            serde_bencode::to_bytes(&skeleton).unwrap().len(),
+            serde_bencode::to_bytes(&created).unwrap().len()
+        );
+        assert!(plan.skeleton(announce, 0).is_err());
+    }
+
+    async fn encoded(root: &Path, creation_date: Option<i64>) -> Vec<u8> {
+        let mut created = Metainfo::create(root, "http://tracker.example/announce", 4)
+            .await
+            .unwrap();
+        created.creation_date = creation_date;
+        serde_bencode::to_bytes(&created).unwrap()
+    }
+
+    #[tokio::test]
+    async fn same_inputs_byte_identical_torrents() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("release");
+        tokio::fs::create_dir_all(root.join("docs")).await.unwrap();
+        for (name, contents) in [
+            ("z.bin", &b"zzz"[..]),
+            ("docs/readme", b"read me"),
+            ("a", b""),
+        ] {
+            tokio::fs::write(root.join(name), contents).await.unwrap();
+        }
+
+        for creation_date in [None, Some(1_700_000_000)] {
+            let first = encoded(&root, creation_date).await;
+            assert_eq!(encoded(&root, creation_date).await, first);
+            // already canonical: sorted keys, nothing for a re-encode to change
+            let (canonical, fixes) = bencode::normalize(&first, KeyPolicy::Strict).unwrap();
+            assert!(fixes.is_empty());
+            assert_eq!(canonical, first);
+            let has_date = first.windows(13).any(|w| w == b"creation date");
+            assert_eq!(has_date, creation_date.is_some());
+        }
+    }
+}
@@ -1,16 +1,45 @@
 use std::arch::x86_64::_rdrand32_step;
-use std::{fmt, net::SocketAddr};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Context};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
 use tokio::{
-    io::{self, AsyncWriteExt},
-    net::TcpStream,
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpSocket, TcpStream},
+    time::timeout,
 };
 
+use crate::error::Error;
+
+pub mod choker;
+pub mod trace;
+
 const PIECE_CHUNK_SZ: u32 = 16 * 1024; // 16KiB
 
+// how many block requests to keep outstanding with a peer unless told otherwise
+pub const DEFAULT_PIPELINE_DEPTH: usize = 5;
+// the id we ask peers to use for ut_metadata messages sent to us
+pub const UT_METADATA_ID: u8 = 1;
+// BEP 9 splits the info dict into pieces of this size
+const METADATA_PIECE_SZ: usize = 16 * 1024;
+// refuse to buffer info dicts bigger than this for a magnet link
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+// how long a peer gets to answer each metadata piece request
+const METADATA_PIECE_TIMEOUT: Duration = Duration::from_secs(30);
+// the longest message a probe will read, enough for a bitfield of 8M pieces;
+// it doesn't know the torrent, so it can't tell how long one should be
+const MAX_INSPECT_MSG: u32 = 1024 * 1024;
+// how much each block's round trip moves a peer's average
+const RTT_WEIGHT: f64 = 0.2;
+
 #[derive(Deserialize, Serialize)]
 pub struct PeerHandshake {
     version: u8,
@@ -25,17 +54,24 @@ impl PeerHandshake {
         PeerHandshake {
             version: 19,
             proto: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            // advertise the extension protocol (BEP 10)
+            reserved: [0, 0, 0, 0, 0, 0x10, 0, 0],
             info_hash,
             peer_id,
         }
     }
 
+    // also advertise the fast extension (BEP 6)
+    fn with_fast(mut self) -> Self {
+        self.reserved[7] |= 0x04;
+        self
+    }
+
     fn to_bytes(&self) -> [u8; 68] {
         let mut buf = [0u8; 68];
         buf[0] = self.version;
         buf[1..20].copy_from_slice(&self.proto);
-        // skipping reserved bytes, they're already zeroed
+        buf[20..28].copy_from_slice(&self.reserved);
         buf[28..48].copy_from_slice(&self.info_hash);
         buf[48..68].copy_from_slice(&self.peer_id);
         buf
@@ -60,6 +96,273 @@ impl PeerHandshake {
     }
 }
 
+// how outgoing peer connections are made: optionally from a fixed local
+// address, and optionally tunneled through a SOCKS5 proxy (independent of
+// how the tracker is reached). there's no TLS client among our dependencies,
+// so a relay that wants TLS is reached through a local tunnel (stunnel,
+// `ssh -D`) that this proxy address points at.
+#[derive(Clone, Copy)]
+pub struct Dialer {
+    pub local: Option<IpAddr>,
+    pub proxy: Option<SocketAddr>,
+}
+
+impl Dialer {
+    pub async fn connect(&self, remote: SocketAddr) -> io::Result<TcpStream> {
+        match self.proxy {
+            None => self.connect_direct(remote).await,
+            Some(proxy) => {
+                let mut conn = self.connect_direct(proxy).await?;
+                socks5_connect(&mut conn, remote).await?;
+                Ok(conn)
+            }
+        }
+    }
+
+    async fn connect_direct(&self, remote: SocketAddr) -> io::Result<TcpStream> {
+        match self.local {
+            None => TcpStream::connect(remote).await,
+            Some(ip) => {
+                let sock = if remote.is_ipv4() {
+                    TcpSocket::new_v4()?
+                } else {
+                    TcpSocket::new_v6()?
+                };
+                sock.bind(SocketAddr::new(ip, 0))?;
+                sock.connect(remote).await
+            }
+        }
+    }
+}
+
+// RFC 1928 CONNECT with no authentication
+async fn socks5_connect(conn: &mut TcpStream, remote: SocketAddr) -> io::Result<()> {
+    let proxy_err = |msg: String| io::Error::new(io::ErrorKind::Other, msg);
+
+    conn.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0u8; 2];
+    conn.read_exact(&mut choice).await?;
+    if choice != [5, 0] {
+        return Err(proxy_err(format!(
+            "SOCKS5 proxy refused no-auth method: {:?}",
+            choice
+        )));
+    }
+
+    let mut req = vec![5, 1, 0];
+    match remote.ip() {
+        IpAddr::V4(v4) => {
+            req.push(1);
+            req.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            req.push(4);
+            req.extend_from_slice(&v6.octets());
+        }
+    }
+    req.extend_from_slice(&remote.port().to_be_bytes());
+    conn.write_all(&req).await?;
+
+    let mut reply = [0u8; 4];
+    conn.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(proxy_err(format!(
+            "SOCKS5 proxy failed to connect to {}: reply code {}",
+            remote, reply[1]
+        )));
+    }
+    // skip the bound address the proxy reports back, then its port
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => conn.read_u8().await? as usize,
+        other => return Err(proxy_err(format!("bad SOCKS5 address type: {}", other))),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    conn.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+pub struct PeerProbe {
+    pub rtt: Option<Duration>,
+    pub peer_id: Option<[u8; 20]>,
+}
+
+// connect (measuring how long that takes) and try a handshake, giving up on
+// either step after `wait`
+pub async fn probe(
+    remote: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    dialer: Dialer,
+    wait: Duration,
+) -> PeerProbe {
+    let start = Instant::now();
+    let mut conn = match timeout(wait, dialer.connect(remote)).await {
+        Ok(Ok(conn)) => conn,
+        _ => {
+            return PeerProbe {
+                rtt: None,
+                peer_id: None,
+            }
+        }
+    };
+    let rtt = start.elapsed();
+
+    let my_hand = PeerHandshake::new(info_hash, peer_id);
+    let mut buf = [0u8; 68];
+    let their_id = timeout(wait, async {
+        conn.write_all(&my_hand.to_bytes()).await?;
+        conn.read_exact(&mut buf).await?;
+        Ok::<_, io::Error>(PeerHandshake::from_bytes(&buf).peer_id)
+    })
+    .await
+    .ok()
+    .and_then(Result::ok);
+
+    PeerProbe {
+        rtt: Some(rtt),
+        peer_id: their_id,
+    }
+}
+
+pub struct PeerInspection {
+    pub peer_id: [u8; 20],
+    pub reserved: [u8; 8],
+    pub bitfield: Option<Vec<u8>>,
+    // only asked for when the peer advertises the extension protocol
+    pub extensions: Option<ExtensionHandshake>,
+    // things the peer did that a well-behaved one wouldn't
+    pub anomalies: Vec<String>,
+}
+
+impl PeerInspection {
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & 0x01 != 0
+    }
+}
+
+// handshake and then wait (up to `wait`) for the peer's bitfield and, if it
+// speaks the extension protocol, its extension handshake, without ever
+// expressing interest or requesting anything
+pub async fn inspect(
+    remote: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    dialer: Dialer,
+    wait: Duration,
+) -> anyhow::Result<PeerInspection> {
+    let mut conn = timeout(wait, dialer.connect(remote))
+        .await
+        .context("timed out connecting to peer")?
+        .context("failed to connect to peer")?;
+
+    let my_hand = PeerHandshake::new(info_hash, peer_id);
+    let mut buf = [0u8; 68];
+    timeout(wait, async {
+        conn.write_all(&my_hand.to_bytes()).await?;
+        conn.read_exact(&mut buf).await
+    })
+    .await
+    .context("timed out waiting for handshake")?
+    .context("failed exchanging handshakes")?;
+    let their_hand = PeerHandshake::from_bytes(&buf);
+    if their_hand.info_hash != info_hash {
+        return Err(anyhow!("peer answered with a different info hash"));
+    }
+
+    let want_extensions = their_hand.reserved[5] & 0x10 != 0;
+    if want_extensions {
+        // an empty `m`: we have nothing to offer, but the peer only sends its
+        // handshake once it has seen one
+        let msg = PeerMessage::Extended {
+            id: 0,
+            payload: ByteBuf::from(serde_bencode::to_bytes(&ExtensionHandshake::default())?),
+        }
+        .to_bytes();
+        conn.write_all(&(msg.len() as u32).to_be_bytes()).await?;
+        conn.write_all(&msg).await?;
+    }
+
+    let mut bitfield = None;
+    let mut extensions = None;
+    let mut anomalies = vec![];
+    // whatever arrived before the wait ran out is still worth reporting
+    let _ = timeout(wait, async {
+        while bitfield.is_none() || (want_extensions && extensions.is_none()) {
+            let len = conn.read_u32().await?;
+            if len == 0 {
+                continue; // keepalive
+            }
+            if len > MAX_INSPECT_MSG {
+                anomalies.push(format!(
+                    "announced a {}-byte message, longer than any it should send",
+                    len
+                ));
+                break;
+            }
+            let mut msg = vec![0u8; len as usize];
+            conn.read_exact(&mut msg).await?;
+            match msg[0] {
+                5 => bitfield = Some(msg.split_off(1)),
+                20 if msg.get(1) == Some(&0) => {
+                    extensions =
+                        Some(crate::bencode::decode(&msg[2..]).map_err(|e| {
+                            io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+                        })?)
+                }
+                _ => {}
+            }
+        }
+        Ok::<_, io::Error>(())
+    })
+    .await;
+
+    Ok(PeerInspection {
+        peer_id: their_hand.peer_id,
+        reserved: their_hand.reserved,
+        bitfield,
+        extensions,
+        anomalies,
+    })
+}
+
+// decodes Azureus-style peer ids, e.g. "-qB4250-..." => "qBittorrent 4.2.5.0"
+pub fn client_name(peer_id: &[u8; 20]) -> Option<String> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let version = std::str::from_utf8(&peer_id[3..7]).ok()?;
+    let name = match code {
+        "AZ" => "Vuze",
+        "BI" => "BiglyBT",
+        "BT" => "BitTorrent",
+        "DE" => "Deluge",
+        "lt" => "libtorrent",
+        "LT" => "libTorrent",
+        "qB" => "qBittorrent",
+        "TR" => "Transmission",
+        "UT" => "\u{b5}Torrent",
+        other => other,
+    };
+    let version = version
+        .chars()
+        .map(String::from)
+        .collect::<Vec<_>>()
+        .join(".");
+    Some(format!("{} {}", name, version))
+}
+
+#[derive(PartialEq)]
 pub enum PeerMessage {
     Choke {},
     Unchoke {},
@@ -86,6 +389,77 @@ pub enum PeerMessage {
         begin: u32,
         length: u32,
     },
+    // BEP 6, only sent once both sides have advertised the fast extension
+    Suggest {
+        index: u32,
+    },
+    HaveAll {},
+    HaveNone {},
+    Reject {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast {
+        index: u32,
+    },
+    // BEP 10: `id` 0 is the extension handshake, anything else is whatever
+    // extension we told the peer to send with that id
+    Extended {
+        id: u8,
+        payload: ByteBuf,
+    },
+}
+
+// the bencoded payload of an extension handshake; unknown keys are ignored
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct ExtensionHandshake {
+    // extension name => the message id the sender wants to receive it as
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+    // our address as the sender sees it, in compact form
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    // the sender's client name and version, e.g. "qBittorrent/4.2.5"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+}
+
+// the bencoded header of a ut_metadata message; `data` messages are followed
+// by the raw bytes of the metadata piece
+#[derive(Serialize, Deserialize, Debug)]
+struct MetadataMsg {
+    msg_type: u8,
+    piece: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<u32>,
+}
+
+const METADATA_REQUEST: u8 = 0;
+const METADATA_DATA: u8 = 1;
+const METADATA_REJECT: u8 = 2;
+
+impl ExtensionHandshake {
+    pub fn ut_metadata(&self) -> Option<u8> {
+        self.m
+            .get("ut_metadata")
+            .and_then(|id| u8::try_from(*id).ok())
+            .filter(|id| *id != 0)
+    }
+
+    pub fn client_version(&self) -> Option<String> {
+        self.v
+            .as_ref()
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+    }
+
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        self.yourip
+            .as_ref()
+            .and_then(|ip| crate::tracker::compact_ip(ip))
+    }
 }
 
 impl fmt::Debug for PeerMessage {
@@ -112,12 +486,38 @@ impl fmt::Debug for PeerMessage {
                 begin,
                 length,
             } => write!(f, "Cancel {{ {}, {}, {} }}", index, begin, length),
+            Self::Suggest { index } => write!(f, "Suggest {{ {} }}", index),
+            Self::HaveAll {} => write!(f, "HaveAll {{  }}"),
+            Self::HaveNone {} => write!(f, "HaveNone {{  }}"),
+            Self::Reject {
+                index,
+                begin,
+                length,
+            } => write!(f, "Reject {{ {}, {}, {} }}", index, begin, length),
+            Self::AllowedFast { index } => write!(f, "AllowedFast {{ {} }}", index),
+            Self::Extended { id, payload } => {
+                write!(f, "Extended {{ {}, {} bytes }}", id, payload.len())
+            }
         }
     }
 }
 
 impl PeerMessage {
     fn from_bytes(buf: &[u8]) -> anyhow::Result<Self> {
+        let min_len = match buf[0] {
+            6 | 8 | 16 => 13,
+            7 => 9,
+            13 | 17 => 5,
+            _ => 1,
+        };
+        if buf.len() < min_len {
+            return Err(anyhow!(
+                "got {} bytes for a type {} PeerMessage, need {}",
+                buf.len(),
+                buf[0],
+                min_len
+            ));
+        }
         match buf[0] {
             0 => Ok(Self::Choke {}),
             1 => Ok(Self::Unchoke {}),
@@ -152,6 +552,28 @@ impl PeerMessage {
                 begin: u32::from_be_bytes(buf[5..9].try_into()?),
                 length: u32::from_be_bytes(buf[9..13].try_into()?),
             }),
+            13 => Ok(Self::Suggest {
+                index: u32::from_be_bytes(buf[1..5].try_into()?),
+            }),
+            14 => Ok(Self::HaveAll {}),
+            15 => Ok(Self::HaveNone {}),
+            16 => Ok(Self::Reject {
+                index: u32::from_be_bytes(buf[1..5].try_into()?),
+                begin: u32::from_be_bytes(buf[5..9].try_into()?),
+                length: u32::from_be_bytes(buf[9..13].try_into()?),
+            }),
+            17 => Ok(Self::AllowedFast {
+                index: u32::from_be_bytes(buf[1..5].try_into()?),
+            }),
+            20 => {
+                if buf.len() < 2 {
+                    return Err(anyhow!("got an Extended message with no extension id"));
+                }
+                Ok(Self::Extended {
+                    id: buf[1],
+                    payload: ByteBuf::from(&buf[2..]),
+                })
+            }
             _ => Err(anyhow!("got unexpected PeerMessage type: {}", buf[0])),
         }
     }
@@ -211,20 +633,89 @@ impl PeerMessage {
                 .chain(length.to_be_bytes().iter())
                 .copied()
                 .collect(),
+            Self::Suggest { index } => [13]
+                .iter()
+                .chain(index.to_be_bytes().iter())
+                .copied()
+                .collect(),
+            Self::HaveAll {} => {
+                vec![14]
+            }
+            Self::HaveNone {} => {
+                vec![15]
+            }
+            Self::Reject {
+                index,
+                begin,
+                length,
+            } => [16]
+                .iter()
+                .chain(index.to_be_bytes().iter())
+                .chain(begin.to_be_bytes().iter())
+                .chain(length.to_be_bytes().iter())
+                .copied()
+                .collect(),
+            Self::AllowedFast { index } => [17]
+                .iter()
+                .chain(index.to_be_bytes().iter())
+                .copied()
+                .collect(),
+            Self::Extended { id, payload } => {
+                [20, *id].iter().chain(payload.iter()).copied().collect()
+            }
+        }
+    }
+}
+
+// BEP 6 canonical allowed-fast set: the first `k` distinct piece indices
+// drawn from repeated SHA-1 of the peer's /24 and the info hash
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: [u8; 20], num_pieces: u32, k: usize) -> Vec<u32> {
+    let k = k.min(num_pieces as usize);
+    let mut set = Vec::with_capacity(k);
+
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(ip) & 0xFFFFFF00).to_be_bytes());
+    x.extend_from_slice(&info_hash);
+
+    while set.len() < k {
+        x = Sha1::digest(x.as_slice()).to_vec();
+        for y in x.chunks(4) {
+            if set.len() >= k {
+                break;
+            }
+            let index =
+                u32::from_be_bytes(y.try_into().expect("sha1 digest is 5 u32s")) % num_pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
         }
     }
+    set
 }
 
 #[derive(Clone)]
 struct PieceRequest {
     index: u32,
     begin: u32,
-    buf: Vec<u8>, // length will be carried as the capacity of this vec
+    length: u32,
+    buf: Vec<u8>,
+    sent: Instant,
+}
+
+// how one block request went, for debugging a particular peer
+pub struct BlockTiming {
+    pub index: u32,
+    pub begin: u32,
+    pub requested: usize,
+    pub received: usize,
+    pub elapsed: Duration,
 }
 
 #[allow(dead_code)]
-pub struct PeerState<'a> {
+pub struct PeerState {
     their_peer_id: [u8; 20],
+    their_reserved: [u8; 8],
+    their_extensions: Option<ExtensionHandshake>,
     im_choked: bool,
     theyre_choked: bool,
     im_interested: bool,
@@ -233,9 +724,25 @@ pub struct PeerState<'a> {
     their_bitfield: Vec<u8>,
     remote: SocketAddr,
     conn: TcpStream,
-    metainfo: &'a crate::types::Metainfo,
+    // unknown until fetched from peers when starting from a magnet link
+    info: Option<crate::metainfo::InfoDict>,
+    // the length of the info dict exactly as it hashes to `info_hash`, which
+    // is what our extension handshake advertises as metadata_size
+    metadata_size: Option<usize>,
+    info_hash: [u8; 20],
     recv_buf: Vec<u8>,
     req_buf: Vec<PieceRequest>,
+    // (index, begin) of requests we cancelled; the peer may have sent the
+    // blocks before it saw the Cancel, but not after answering a later request
+    cancelled: Vec<(u32, u32)>,
+    // block requests to keep outstanding at once; more hides more latency
+    pipeline_depth: usize,
+    // kept only once asked for with record_block_timings
+    block_timings: Option<Vec<BlockTiming>>,
+    // average time from asking for a block to having all of it
+    block_rtt: Option<Duration>,
+    // the peer connected to us rather than the other way round
+    accepted: bool,
 }
 
 // state machine
@@ -246,15 +753,33 @@ pub struct PeerState<'a> {
 //   wait for some bytes
 //   try to read a message from what was received
 
-impl<'a> PeerState<'a> {
+impl PeerState {
     pub async fn connect(
         remote: SocketAddr,
-        metainfo: &'a crate::types::Metainfo,
+        metainfo: &crate::metainfo::Metainfo,
+        my_peer_id: [u8; 20],
+        dialer: Dialer,
     ) -> anyhow::Result<Self> {
-        let mut peerconn = TcpStream::connect(remote)
+        let mut peer =
+            Self::connect_by_hash(remote, metainfo.info_hash()?, my_peer_id, dialer).await?;
+        peer.info = Some(metainfo.info.clone());
+        peer.metadata_size = metainfo.info_bytes.as_ref().map(Vec::len);
+        Ok(peer)
+    }
+
+    // connects knowing only the info hash, e.g. from a magnet link; pieces
+    // can't be fetched until `set_info` is called
+    pub async fn connect_by_hash(
+        remote: SocketAddr,
+        info_hash: [u8; 20],
+        my_peer_id: [u8; 20],
+        dialer: Dialer,
+    ) -> anyhow::Result<Self> {
+        let mut peerconn = dialer
+            .connect(remote)
             .await
             .context("failed to connect to peer")?;
-        let my_hand = PeerHandshake::new(metainfo.info.hash()?, *b"00112233445566778899");
+        let my_hand = PeerHandshake::new(info_hash, my_peer_id);
         peerconn
             .write_all(&my_hand.to_bytes())
             .await
@@ -262,28 +787,138 @@ impl<'a> PeerState<'a> {
 
         Ok(PeerState {
             their_peer_id: [0; 20],
+            their_reserved: [0; 8],
+            their_extensions: None,
             im_choked: true,
-            theyre_choked: false,
+            theyre_choked: true,
             im_interested: false,
             theyre_interested: false,
             my_bitfield: vec![],
             their_bitfield: vec![],
             remote,
             conn: peerconn,
-            metainfo,
+            info: None,
+            metadata_size: None,
+            info_hash,
             recv_buf: vec![],
             req_buf: vec![],
+            cancelled: vec![],
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            block_timings: None,
+            block_rtt: None,
+            accepted: false,
         })
     }
 
+    // takes over a connection the peer opened to us: their handshake must
+    // name `info`'s torrent before we answer with ours
+    pub async fn accept(
+        conn: TcpStream,
+        remote: SocketAddr,
+        info: crate::metainfo::InfoDict,
+        info_hash: [u8; 20],
+        my_peer_id: [u8; 20],
+    ) -> anyhow::Result<Self> {
+        let mut peer = PeerState {
+            their_peer_id: [0; 20],
+            their_reserved: [0; 8],
+            their_extensions: None,
+            im_choked: true,
+            theyre_choked: true,
+            im_interested: false,
+            theyre_interested: false,
+            my_bitfield: vec![],
+            their_bitfield: vec![],
+            remote,
+            conn,
+            info: Some(info),
+            metadata_size: None,
+            info_hash,
+            recv_buf: vec![],
+            req_buf: vec![],
+            cancelled: vec![],
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            block_timings: None,
+            block_rtt: None,
+            accepted: true,
+        };
+        peer.wait_for_handshake().await?;
+        let my_hand = PeerHandshake::new(info_hash, my_peer_id).with_fast();
+        peer.conn
+            .write_all(&my_hand.to_bytes())
+            .await
+            .context("failed to send handshake to peer")?;
+        Ok(peer)
+    }
+
     pub fn choking(&self) -> bool {
         self.im_choked
     }
 
+    // whether we are choking the peer
+    pub fn choked(&self) -> bool {
+        self.theyre_choked
+    }
+
+    // whether the peer wants pieces from us
+    pub fn interested(&self) -> bool {
+        self.theyre_interested
+    }
+
+    pub async fn send_bitfield(&mut self, bitfield: Vec<u8>) -> anyhow::Result<()> {
+        self.send_msg(PeerMessage::Bitfield {
+            sent_indices: ByteBuf::from(bitfield.clone()),
+        })
+        .await?;
+        self.my_bitfield = bitfield;
+        Ok(())
+    }
+
+    // chokes or unchokes the peer, saying nothing if that's no change
+    pub async fn set_choked(&mut self, choked: bool) -> anyhow::Result<()> {
+        if self.theyre_choked != choked {
+            self.send_msg(if choked {
+                PeerMessage::Choke {}
+            } else {
+                PeerMessage::Unchoke {}
+            })
+            .await?;
+            self.theyre_choked = choked;
+        }
+        Ok(())
+    }
+
+    // answers a Request; returns how many bytes of piece data went out
+    pub async fn send_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    ) -> anyhow::Result<usize> {
+        let len = block.len();
+        self.send_msg(PeerMessage::Piece {
+            index,
+            begin,
+            piece: ByteBuf::from(block),
+        })
+        .await?;
+        Ok(len)
+    }
+
     pub fn bitfield(&self) -> &[u8] {
         &self.their_bitfield
     }
 
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.their_bitfield
+            .get(index as usize / 8)
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
     pub async fn indicate_interest(&mut self) -> anyhow::Result<()> {
         if !self.im_interested {
             self.send_msg(PeerMessage::Interested {}).await?;
@@ -292,17 +927,203 @@ impl<'a> PeerState<'a> {
         Ok(())
     }
 
+    pub fn set_pipeline_depth(&mut self, depth: usize) {
+        self.pipeline_depth = depth.max(1);
+    }
+
+    // starts keeping a BlockTiming for every block received
+    pub fn record_block_timings(&mut self) {
+        self.block_timings.get_or_insert_with(Vec::new);
+    }
+
+    // the timings recorded since the last call
+    pub fn take_block_timings(&mut self) -> Vec<BlockTiming> {
+        self.block_timings
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    // how long the peer has been taking to answer block requests, on average.
+    // with requests pipelined this includes waiting behind earlier blocks,
+    // so it reflects the peer's bandwidth as well as its distance.
+    pub fn block_rtt(&self) -> Option<Duration> {
+        self.block_rtt
+    }
+
     pub fn remote_peer_id(&self) -> [u8; 20] {
         self.their_peer_id
     }
 
+    pub fn set_info(&mut self, info: crate::metainfo::InfoDict) {
+        self.info = Some(info);
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.their_reserved[5] & 0x10 != 0
+    }
+
+    // whether the fast extension is in use on this connection; we only
+    // advertise it on connections peers open to us
+    pub fn fast(&self) -> bool {
+        self.accepted && self.their_reserved[7] & 0x04 != 0
+    }
+
+    // tells the peer it may request these pieces even while choked
+    pub async fn send_allowed_fast(&mut self, indices: &[u32]) -> anyhow::Result<()> {
+        for index in indices {
+            self.send_msg(PeerMessage::AllowedFast { index: *index })
+                .await?;
+        }
+        Ok(())
+    }
+
+    // refuses a Request, which the fast extension wants rather than silence
+    pub async fn send_reject(&mut self, index: u32, begin: u32, length: u32) -> anyhow::Result<()> {
+        self.send_msg(PeerMessage::Reject {
+            index,
+            begin,
+            length,
+        })
+        .await?;
+        Ok(())
+    }
+
+    // the peer's extension handshake, once it has arrived
+    pub fn extensions(&self) -> Option<&ExtensionHandshake> {
+        self.their_extensions.as_ref()
+    }
+
+    pub async fn send_extension_handshake(&mut self) -> anyhow::Result<()> {
+        let hs = ExtensionHandshake {
+            m: BTreeMap::from([("ut_metadata".to_string(), UT_METADATA_ID.into())]),
+            metadata_size: self.metadata_size.map(|size| size as i64),
+            yourip: None,
+            v: None,
+        };
+        self.send_msg(PeerMessage::Extended {
+            id: 0,
+            payload: ByteBuf::from(serde_bencode::to_bytes(&hs)?),
+        })
+        .await?;
+        Ok(())
+    }
+
+    // sends our extension handshake and waits until the peer's arrives
+    pub async fn exchange_extension_handshakes(&mut self) -> anyhow::Result<ExtensionHandshake> {
+        if !self.supports_extensions() {
+            return Err(
+                Error::Protocol("peer does not support the extension protocol".into()).into(),
+            );
+        }
+        self.send_extension_handshake().await?;
+        loop {
+            if let Some(hs) = &self.their_extensions {
+                return Ok(hs.clone());
+            }
+            for m in self.poll().await? {
+                eprintln!("waiting for extension handshake, got: {:?}", m);
+            }
+        }
+    }
+
+    // fetches the info dict from the peer with ut_metadata (BEP 9), checks it
+    // against the info hash we connected with, and remembers it. returns it
+    // decoded and as the raw bytes that were hashed. the extension handshakes
+    // must already have been exchanged.
+    pub async fn fetch_metadata(&mut self) -> anyhow::Result<(crate::metainfo::InfoDict, Vec<u8>)> {
+        let no_metadata = || Error::Protocol("peer does not offer ut_metadata".into());
+        let ext = self.their_extensions.as_ref().ok_or_else(no_metadata)?;
+        let their_id = ext.ut_metadata().ok_or_else(no_metadata)?;
+        let size = ext
+            .metadata_size
+            .and_then(|s| usize::try_from(s).ok())
+            .filter(|s| (1..=MAX_METADATA_SIZE).contains(s))
+            .ok_or_else(|| Error::Protocol("peer sent no usable metadata_size".into()))?;
+
+        let num_pieces = size.div_ceil(METADATA_PIECE_SZ);
+        let mut metadata = vec![0u8; size];
+        for piece in 0..num_pieces {
+            let req = MetadataMsg {
+                msg_type: METADATA_REQUEST,
+                piece: piece as u32,
+                total_size: None,
+            };
+            self.send_msg(PeerMessage::Extended {
+                id: their_id,
+                payload: ByteBuf::from(serde_bencode::to_bytes(&req)?),
+            })
+            .await?;
+
+            let reply = timeout(METADATA_PIECE_TIMEOUT, async {
+                loop {
+                    for m in self.poll().await? {
+                        let payload = match m {
+                            PeerMessage::Extended { id, payload } if id == UT_METADATA_ID => {
+                                payload
+                            }
+                            other => {
+                                eprintln!("waiting for metadata piece {}, got: {:?}", piece, other);
+                                continue;
+                            }
+                        };
+                        let header_len = crate::bencode::value_len(&payload)?;
+                        let header: MetadataMsg = crate::bencode::decode(&payload[..header_len])
+                            .map_err(|e| {
+                                Error::Protocol(format!("malformed ut_metadata message: {}", e))
+                            })?;
+                        match header.msg_type {
+                            METADATA_DATA if header.piece == piece as u32 => {
+                                return anyhow::Ok(payload[header_len..].to_vec());
+                            }
+                            METADATA_REJECT => {
+                                return Err(Error::Protocol(format!(
+                                    "peer rejected request for metadata piece {}",
+                                    piece
+                                ))
+                                .into());
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            })
+            .await;
+            let data = reply.map_err(|_| {
+                Error::Protocol(format!(
+                    "no reply to request for metadata piece {} within {:?}",
+                    piece, METADATA_PIECE_TIMEOUT
+                ))
+            })??;
+
+            let start = piece * METADATA_PIECE_SZ;
+            let expected = METADATA_PIECE_SZ.min(size - start);
+            if data.len() != expected {
+                return Err(Error::Protocol(format!(
+                    "metadata piece {} is {} bytes, expected {}",
+                    piece,
+                    data.len(),
+                    expected
+                ))
+                .into());
+            }
+            metadata[start..start + expected].copy_from_slice(&data);
+        }
+
+        if Sha1::digest(metadata.as_slice()).as_slice() != self.info_hash {
+            return Err(Error::Protocol("metadata does not match the info hash".into()).into());
+        }
+        let info = crate::metainfo::InfoDict::from_bytes(&metadata)
+            .map_err(|e| Error::Metainfo(format!("peer sent an undecodable info dict: {:#}", e)))?;
+        self.info = Some(info.clone());
+        self.metadata_size = Some(metadata.len());
+        Ok((info, metadata))
+    }
+
     pub async fn wait_for_handshake(&mut self) -> anyhow::Result<()> {
         'ultimate: loop {
             'tryread: loop {
-                self.conn
-                    .readable()
-                    .await
-                    .expect("failed waiting for data from peer");
+                self.conn.readable().await.map_err(Error::Io)?;
                 // eprintln!("peer connection should be readable");
                 match self.conn.try_read_buf(&mut self.recv_buf) {
                     Ok(0) => {
@@ -315,7 +1136,7 @@ impl<'a> PeerState<'a> {
                     Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                         eprintln!("false positive from peercon.readable()");
                     }
-                    Err(e) => panic!("some other error from peer: {}", e),
+                    Err(e) => return Err(Error::Io(e).into()),
                 }
             }
 
@@ -323,8 +1144,15 @@ impl<'a> PeerState<'a> {
                 let new_buf = self.recv_buf.split_off(68);
                 let hs_bytes = &self.recv_buf;
                 let their_hand = PeerHandshake::from_bytes(hs_bytes);
+                if their_hand.version != 19 || their_hand.proto != *b"BitTorrent protocol" {
+                    return Err(anyhow!("peer did not speak the BitTorrent protocol"));
+                }
+                if their_hand.info_hash != self.info_hash {
+                    return Err(anyhow!("peer answered with a different info hash"));
+                }
                 self.recv_buf = new_buf;
                 self.their_peer_id = their_hand.peer_id;
+                self.their_reserved = their_hand.reserved;
                 break 'ultimate;
             } else {
                 eprintln!("not enough bytes to start");
@@ -337,26 +1165,76 @@ impl<'a> PeerState<'a> {
     //   * currently choked
     //   * peer disconnected before i finished downloading
     pub async fn get_piece(&mut self, piece_idx: u32) -> anyhow::Result<Vec<u8>> {
-        // TODO: check/set interested state, message about the change if needed
+        self.get_piece_unless(piece_idx, std::future::pending())
+            .await?
+            .context("piece fetch abandoned")
+    }
 
+    // like get_piece, but if `abandon` completes first the requests still
+    // outstanding are cancelled and None is returned
+    pub async fn get_piece_unless(
+        &mut self,
+        piece_idx: u32,
+        abandon: impl Future<Output = ()>,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
         let piece_len = self
-            .metainfo
             .info
-            .piece_length()
-            .min(self.metainfo.info.length() - self.metainfo.info.piece_length() * piece_idx);
-        eprintln!("expecting to get {} bytes for this piece", piece_len);
+            .as_ref()
+            .context("can't fetch pieces before the torrent's metadata is known")?
+            .piece_size(piece_idx);
+        self.get_part_unless(piece_idx, 0, piece_len, abandon).await
+    }
+
+    // like get_piece_unless, but only for the `length` bytes at `begin` in
+    // the piece, for pieces big enough to be split between peers
+    pub async fn get_part_unless(
+        &mut self,
+        piece_idx: u32,
+        begin: u32,
+        length: u32,
+        abandon: impl Future<Output = ()>,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        tokio::pin!(abandon);
+        // TODO: check/set interested state, message about the change if needed
+        let info = self
+            .info
+            .as_ref()
+            .context("can't fetch pieces before the torrent's metadata is known")?;
+        if piece_idx as usize >= info.num_pieces() {
+            return Err(anyhow!(
+                "piece index {} out of range, torrent has {} pieces",
+                piece_idx,
+                info.num_pieces()
+            ));
+        }
+        let piece_len = info.piece_size(piece_idx);
+        if begin
+            .checked_add(length)
+            .map_or(true, |end| end > piece_len)
+        {
+            return Err(anyhow!(
+                "{} bytes at {} run past the end of piece {}, which has {}",
+                length,
+                begin,
+                piece_idx,
+                piece_len
+            ));
+        }
+
+        eprintln!("expecting to get {} bytes for this piece", length);
+        let num_chunks = length.div_ceil(PIECE_CHUNK_SZ);
 
-        while self.req_buf.iter().map(|rb| rb.buf.len()).sum::<usize>() < piece_len as usize {
-            while self.req_buf.len() < (piece_len.div_ceil(PIECE_CHUNK_SZ)).try_into()?
-                && self.req_buf.iter().filter(|rb| rb.buf.is_empty()).count() < 5
+        while self.req_buf.iter().map(|rb| rb.buf.len()).sum::<usize>() < length as usize {
+            while self.req_buf.len() < num_chunks.try_into()?
+                && self.req_buf.iter().filter(|rb| rb.buf.is_empty()).count() < self.pipeline_depth
             {
                 let chunk_to_request = {
-                    let chunks_left: Vec<u32> = (0..(piece_len.div_ceil(PIECE_CHUNK_SZ)))
+                    let chunks_left: Vec<u32> = (0..num_chunks)
                         .filter(|potential_idx| {
                             !self
                                 .req_buf
                                 .iter()
-                                .any(|rb| rb.begin == (PIECE_CHUNK_SZ * potential_idx))
+                                .any(|rb| rb.begin == begin + PIECE_CHUNK_SZ * potential_idx)
                         })
                         .collect();
                     // eprintln!("chunks left: {:?}", chunks_left);
@@ -374,9 +1252,8 @@ impl<'a> PeerState<'a> {
                     chunk_to_request, piece_idx
                 );
 
-                let chunk_begin = PIECE_CHUNK_SZ * chunk_to_request;
-                let chunk_length =
-                    PIECE_CHUNK_SZ.min(piece_len - PIECE_CHUNK_SZ * chunk_to_request);
+                let chunk_begin = begin + PIECE_CHUNK_SZ * chunk_to_request;
+                let chunk_length = PIECE_CHUNK_SZ.min(length - PIECE_CHUNK_SZ * chunk_to_request);
                 // eprintln!("...which corresponds to begin={} and length={}", chunk_begin, chunk_length);
 
                 self.send_msg(PeerMessage::Request {
@@ -389,31 +1266,60 @@ impl<'a> PeerState<'a> {
                 self.req_buf.push(PieceRequest {
                     index: piece_idx,
                     begin: chunk_begin,
+                    length: chunk_length,
                     buf: Vec::with_capacity(chunk_length.try_into()?),
+                    sent: Instant::now(),
                 });
             }
-            let msgs = self.poll().await?;
+            // only the wait for messages is given up; a request half
+            // written would garble the stream
+            let msgs = tokio::select! {
+                msgs = self.poll() => msgs?,
+                _ = &mut abandon => {
+                    self.cancel_requests().await?;
+                    return Ok(None);
+                }
+            };
             for m in msgs {
                 eprintln!("waiting for piece, got: {:?}", m);
             }
         }
         eprintln!("got all the chunks of the piece");
 
-        assert_eq!(
-            u32::try_from(self.req_buf.iter().map(|pr| pr.buf.len()).sum::<usize>())?,
-            piece_len,
-            "sum of bufs vs computed len do not match"
-        );
-        let mut piece_bytes = vec![0; piece_len.try_into()?];
+        let received = u32::try_from(self.req_buf.iter().map(|pr| pr.buf.len()).sum::<usize>())?;
+        if received != length {
+            return Err(Error::Protocol(format!(
+                "peer sent {} bytes for a {} byte piece",
+                received, length
+            ))
+            .into());
+        }
+        let mut piece_bytes = vec![0; length.try_into()?];
         for pr in self.req_buf.iter_mut() {
-            piece_bytes.splice(
-                pr.begin as usize..pr.begin as usize + pr.buf.len(),
-                pr.buf.clone(),
-            );
+            let at = (pr.begin - begin) as usize;
+            piece_bytes.splice(at..at + pr.buf.len(), pr.buf.clone());
             pr.buf.clear();
         }
         self.req_buf.clear();
-        Ok(piece_bytes)
+        Ok(Some(piece_bytes))
+    }
+
+    async fn cancel_requests(&mut self) -> anyhow::Result<()> {
+        let outstanding: Vec<PieceRequest> = self
+            .req_buf
+            .drain(..)
+            .filter(|pr| pr.buf.is_empty())
+            .collect();
+        for pr in outstanding {
+            self.send_msg(PeerMessage::Cancel {
+                index: pr.index,
+                begin: pr.begin,
+                length: pr.length,
+            })
+            .await?;
+            self.cancelled.push((pr.index, pr.begin));
+        }
+        Ok(())
     }
 
     pub async fn poll(&mut self) -> anyhow::Result<Vec<PeerMessage>> {
@@ -478,7 +1384,7 @@ impl<'a> PeerState<'a> {
 
     async fn send_msg(&mut self, msg: PeerMessage) -> anyhow::Result<usize> {
         let mut to_send = msg.to_bytes();
-        let mut bytes_out = Vec::from(to_send.len().to_be_bytes());
+        let mut bytes_out = Vec::from(u32::try_from(to_send.len())?.to_be_bytes());
         bytes_out.append(&mut to_send);
         self.conn
             .write_all(&bytes_out)
@@ -498,55 +1404,132 @@ impl<'a> PeerState<'a> {
                 self.im_choked = false;
                 Ok(msg)
             }
+            PeerMessage::Interested {} => {
+                self.theyre_interested = true;
+                Ok(msg)
+            }
+            PeerMessage::NotInterested {} => {
+                self.theyre_interested = false;
+                Ok(msg)
+            }
+            // serving requests is up to the caller, who knows what's on disk
+            PeerMessage::Request { .. } | PeerMessage::Cancel { .. } => Ok(msg),
             PeerMessage::Bitfield { ref sent_indices } => {
                 self.their_bitfield = sent_indices.to_vec();
                 Ok(msg)
             }
+            PeerMessage::HaveAll {} => {
+                let num_pieces = self.info.as_ref().map_or(0, |info| info.num_pieces());
+                self.their_bitfield = vec![0xff; num_pieces.div_ceil(8)];
+                Ok(msg)
+            }
+            PeerMessage::HaveNone {} => {
+                self.their_bitfield.clear();
+                Ok(msg)
+            }
+            PeerMessage::Suggest { .. }
+            | PeerMessage::Reject { .. }
+            | PeerMessage::AllowedFast { .. } => Ok(msg),
+            PeerMessage::Have { index } => {
+                // before the metadata is known, as many pieces as the biggest
+                // info dict we'd accept could list
+                let num_pieces = self
+                    .info
+                    .as_ref()
+                    .map_or(MAX_METADATA_SIZE / 20, |info| info.num_pieces());
+                if index as usize >= num_pieces {
+                    return Err(Error::Protocol(format!(
+                        "peer has piece {}, but the torrent has {}",
+                        index, num_pieces
+                    ))
+                    .into());
+                }
+                let byte = index as usize / 8;
+                if self.their_bitfield.len() <= byte {
+                    self.their_bitfield.resize(byte + 1, 0);
+                }
+                self.their_bitfield[byte] |= 0x80 >> (index % 8);
+                Ok(msg)
+            }
             PeerMessage::Piece {
                 index,
                 begin,
                 ref piece,
             } => {
-                let pr_idx = {
-                    let mut pr_iter = self
-                        .req_buf
-                        .iter()
-                        .enumerate()
-                        .filter(|(_, pr)| pr.index == index && pr.begin == begin);
-                    if pr_iter.clone().count() < 1 {
-                        return Err(anyhow!(
-                            "got a Piece message for which i have no outstanding Request"
-                        ));
-                    }
-                    pr_iter.next().unwrap().0
-                };
-                let pr = &mut self.req_buf[pr_idx];
-                if pr.buf.capacity() < piece.len() {
-                    return Err(anyhow!("the received Piece data is bigger than requested"));
+                if let Some(pos) = self.cancelled.iter().position(|c| *c == (index, begin)) {
+                    self.cancelled.swap_remove(pos);
+                    return Ok(msg);
                 }
+                let pr_idx = self
+                    .req_buf
+                    .iter()
+                    .position(|pr| pr.index == index && pr.begin == begin)
+                    .ok_or_else(|| {
+                        Error::Protocol(
+                            "got a Piece message for which i have no outstanding Request".into(),
+                        )
+                    })?;
+                // the peer answers requests in order, so anything it was
+                // going to send for a cancelled one came before this
+                self.cancelled.clear();
+                let pr = &mut self.req_buf[pr_idx];
                 if !pr.buf.is_empty() {
                     return Err(anyhow!("got a Piece for which i already have data"));
                 }
+                let elapsed = pr.sent.elapsed();
+                self.block_rtt = Some(match self.block_rtt {
+                    Some(avg) => avg.mul_f64(1.0 - RTT_WEIGHT) + elapsed.mul_f64(RTT_WEIGHT),
+                    None => elapsed,
+                });
+                if let Some(timings) = self.block_timings.as_mut() {
+                    timings.push(BlockTiming {
+                        index,
+                        begin,
+                        requested: pr.length as usize,
+                        received: piece.len(),
+                        elapsed,
+                    });
+                }
+                // a short or empty block would leave the piece waiting forever
+                // on bytes that were never asked for again
+                if piece.len() != pr.length as usize {
+                    return Err(Error::Protocol(format!(
+                        "peer sent {} bytes for the block at {} of piece {}, {} were requested",
+                        piece.len(),
+                        begin,
+                        index,
+                        pr.length
+                    ))
+                    .into());
+                }
                 pr.buf.resize(piece.len(), 0);
                 pr.buf.copy_from_slice(piece);
 
                 Ok(msg)
             }
-            _ => {
-                eprintln!("non-bitfield message");
-                Err(anyhow!("got unexpected PeerMessage kind: {:?}", msg))
+            PeerMessage::Extended { id: 0, ref payload } => {
+                let hs: ExtensionHandshake = crate::bencode::decode(payload).map_err(|e| {
+                    Error::Protocol(format!("malformed extension handshake: {}", e))
+                })?;
+                if let Some(ip) = hs.your_ip() {
+                    eprintln!("peer {} sees us as {}", self.remote, ip);
+                }
+                self.their_extensions = Some(hs);
+                Ok(msg)
             }
+            PeerMessage::Extended { .. } => Ok(msg),
         }
     }
 
     fn try_read_msg(&mut self) -> anyhow::Result<Option<PeerMessage>> {
         let buf_len = self.recv_buf.len();
         if buf_len >= 4 {
-            let need = u32::from_be_bytes(
-                self.recv_buf[..4]
-                    .try_into()
-                    .expect("recv_buf.len() was supposed to be >=4"),
-            );
+            let need = u32::from_be_bytes([
+                self.recv_buf[0],
+                self.recv_buf[1],
+                self.recv_buf[2],
+                self.recv_buf[3],
+            ]);
             if need == 0 {
                 // a "keepalive"
                 self.recv_buf = self.recv_buf.split_off(4);
@@ -566,3 +1549,382 @@ impl<'a> PeerState<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [0x11; 20];
+
+    // a torrent of `num_pieces` pieces of `piece_length` bytes
+    fn test_info(piece_length: u32, num_pieces: usize) -> crate::metainfo::InfoDict {
+        crate::metainfo::InfoDict::SingleFile {
+            name: "test".to_string(),
+            piece_length,
+            pieces: ByteBuf::from(vec![0; num_pieces * 20]),
+            length: u64::from(piece_length) * num_pieces as u64,
+            private: None,
+        }
+    }
+
+    // our side of a connection over loopback, and the socket a test plays the
+    // remote peer on, with handshakes already exchanged
+    async fn connected(info: crate::metainfo::InfoDict) -> (PeerState, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        remote
+            .write_all(&PeerHandshake::new(INFO_HASH, [0x22; 20]).to_bytes())
+            .await
+            .unwrap();
+        let (conn, addr) = listener.accept().await.unwrap();
+        let peer = PeerState::accept(conn, addr, info, INFO_HASH, [0x33; 20])
+            .await
+            .unwrap();
+        let mut theirs = [0; 68];
+        remote.read_exact(&mut theirs).await.unwrap();
+        (peer, remote)
+    }
+
+    async fn send(remote: &mut TcpStream, msg: PeerMessage) {
+        let bytes = msg.to_bytes();
+        let framed = [&(bytes.len() as u32).to_be_bytes()[..], &bytes].concat();
+        remote.write_all(&framed).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn inspect_reports_oversized_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut theirs = [0; 68];
+            conn.read_exact(&mut theirs).await.unwrap();
+            let mut hand = PeerHandshake::new(INFO_HASH, [0x22; 20]);
+            hand.reserved = [0; 8];
+            conn.write_all(&hand.to_bytes()).await.unwrap();
+            conn.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+            conn
+        });
+        let dialer = Dialer {
+            local: None,
+            proxy: None,
+        };
+        let report = inspect(addr, INFO_HASH, [0x33; 20], dialer, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(report.bitfield.is_none());
+        assert_eq!(
+            report.anomalies,
+            ["announced a 4294967295-byte message, longer than any it should send"]
+        );
+        remote.await.unwrap();
+    }
+
+    // polls until at least one whole message has arrived
+    async fn receive(peer: &mut PeerState) -> anyhow::Result<Vec<PeerMessage>> {
+        loop {
+            let msgs = peer.poll().await?;
+            if !msgs.is_empty() {
+                return Ok(msgs);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn have_for_missing_piece_is_a_protocol_error() {
+        let (mut peer, mut remote) = connected(test_info(16384, 10)).await;
+        send(&mut remote, PeerMessage::Have { index: 9 }).await;
+        receive(&mut peer).await.unwrap();
+        assert!(peer.has_piece(9));
+
+        send(&mut remote, PeerMessage::Have { index: u32::MAX }).await;
+        let err = receive(&mut peer).await.unwrap_err();
+        assert!(err.to_string().contains("torrent has 10"), "{:#}", err);
+        assert!(peer.bitfield().len() <= 2);
+    }
+
+    #[tokio::test]
+    async fn blocks_of_the_wrong_length_fail_the_piece() {
+        for sent in [0, 100, 16385] {
+            let (mut peer, mut remote) = connected(test_info(16384, 1)).await;
+            let answer = async {
+                let mut request = [0; 17];
+                remote.read_exact(&mut request).await.unwrap();
+                let piece = ByteBuf::from(vec![0; sent]);
+                let block = PeerMessage::Piece {
+                    index: 0,
+                    begin: 0,
+                    piece,
+                };
+                send(&mut remote, block).await;
+            };
+            let (fetched, _) = tokio::join!(peer.get_piece(0), answer);
+            let err = fetched.unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "peer protocol violation: peer sent {} bytes for the block at 0 of piece 0, 16384 were requested",
+                    sent
+                )
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_part_of_a_piece() {
+        let (mut peer, mut remote) = connected(test_info(3 * 16384, 1)).await;
+        let answer = async {
+            for _ in 0..2 {
+                let mut request = [0; 17];
+                remote.read_exact(&mut request).await.unwrap();
+                let begin = u32::from_be_bytes(request[9..13].try_into().unwrap());
+                let length = u32::from_be_bytes(request[13..17].try_into().unwrap());
+                let block = PeerMessage::Piece {
+                    index: 0,
+                    begin,
+                    piece: ByteBuf::from(vec![(begin / 16384) as u8; length as usize]),
+                };
+                send(&mut remote, block).await;
+            }
+        };
+        let part = peer.get_part_unless(0, 16384, 20000, std::future::pending());
+        let (fetched, _) = tokio::join!(part, answer);
+        let fetched = fetched.unwrap().unwrap();
+        assert_eq!(fetched, [vec![1; 16384], vec![2; 3616]].concat());
+
+        let past_end = peer.get_part_unless(0, 40000, 10000, std::future::pending());
+        assert!(past_end.await.is_err());
+    }
+
+    // frames as they appear on the wire after the length prefix
+    fn golden_messages() -> Vec<(&'static str, Vec<u8>, PeerMessage)> {
+        vec![
+            ("choke", vec![0], PeerMessage::Choke {}),
+            ("unchoke", vec![1], PeerMessage::Unchoke {}),
+            ("interested", vec![2], PeerMessage::Interested {}),
+            ("not interested", vec![3], PeerMessage::NotInterested {}),
+            (
+                "have",
+                vec![4, 0, 0, 0x01, 0x2c],
+                PeerMessage::Have { index: 300 },
+            ),
+            (
+                "bitfield",
+                vec![5, 0xff, 0xff, 0xe0],
+                PeerMessage::Bitfield {
+                    sent_indices: ByteBuf::from(vec![0xff, 0xff, 0xe0]),
+                },
+            ),
+            (
+                "request",
+                vec![6, 0, 0, 0, 7, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+                PeerMessage::Request {
+                    index: 7,
+                    begin: 16384,
+                    length: 16384,
+                },
+            ),
+            (
+                "piece",
+                vec![7, 0, 0, 0, 7, 0, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef],
+                PeerMessage::Piece {
+                    index: 7,
+                    begin: 0,
+                    piece: ByteBuf::from(vec![0xde, 0xad, 0xbe, 0xef]),
+                },
+            ),
+            (
+                "cancel",
+                vec![8, 0, 0, 0, 7, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+                PeerMessage::Cancel {
+                    index: 7,
+                    begin: 16384,
+                    length: 16384,
+                },
+            ),
+            (
+                "suggest",
+                vec![13, 0, 0, 0, 42],
+                PeerMessage::Suggest { index: 42 },
+            ),
+            ("have all", vec![14], PeerMessage::HaveAll {}),
+            ("have none", vec![15], PeerMessage::HaveNone {}),
+            (
+                "reject",
+                vec![16, 0, 0, 0, 7, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+                PeerMessage::Reject {
+                    index: 7,
+                    begin: 16384,
+                    length: 16384,
+                },
+            ),
+            (
+                "allowed fast",
+                vec![17, 0, 0, 0, 42],
+                PeerMessage::AllowedFast { index: 42 },
+            ),
+            (
+                "ut_metadata request",
+                [&[20, 2][..], b"d8:msg_typei0e5:piecei0ee"].concat(),
+                PeerMessage::Extended {
+                    id: 2,
+                    payload: ByteBuf::from(b"d8:msg_typei0e5:piecei0ee".to_vec()),
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn golden_messages_decode() {
+        for (name, bytes, expected) in golden_messages() {
+            let msg = PeerMessage::from_bytes(&bytes).unwrap();
+            assert_eq!(msg, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn golden_messages_encode() {
+        for (name, bytes, msg) in golden_messages() {
+            assert_eq!(msg.to_bytes(), bytes, "{}", name);
+        }
+    }
+
+    #[test]
+    fn short_messages_are_rejected() {
+        for bytes in [
+            &[4, 0, 0, 1][..],
+            &[6, 0, 0, 0, 7, 0, 0, 0x40, 0, 0, 0, 0x40][..],
+            &[7, 0, 0, 0, 7, 0, 0, 0][..],
+            &[8, 0, 0, 0][..],
+            &[13, 0, 0][..],
+            &[16, 0, 0, 0, 7, 0, 0, 0x40, 0][..],
+            &[17, 0][..],
+            &[20][..],
+        ] {
+            assert!(PeerMessage::from_bytes(bytes).is_err(), "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn handshake_round_trips() {
+        // as sent by qBittorrent 4.2.5: extension protocol, fast and DHT bits
+        let mut bytes = vec![19];
+        bytes.extend_from_slice(b"BitTorrent protocol");
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        bytes.extend_from_slice(&hex::decode("d69f91e6b2ae4c542468d1073a71d4ea13879a7f").unwrap());
+        bytes.extend_from_slice(b"-qB4250-8bA1x_Ks3fQz");
+
+        let hs = PeerHandshake::from_bytes(&bytes);
+        assert_eq!(hs.version, 19);
+        assert_eq!(&hs.proto, b"BitTorrent protocol");
+        assert_eq!(hs.reserved, [0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        assert_eq!(&hs.peer_id, b"-qB4250-8bA1x_Ks3fQz");
+        assert_eq!(hs.to_bytes().to_vec(), bytes);
+        assert_eq!(
+            client_name(&hs.peer_id).as_deref(),
+            Some("qBittorrent 4.2.5.0")
+        );
+    }
+
+    #[test]
+    fn allowed_fast_set_matches_bep_6() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        assert_eq!(
+            allowed_fast_set(ip, [0xaa; 20], 1313, 7),
+            [1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, [0xaa; 20], 1313, 9),
+            [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        // only the /24 counts
+        assert_eq!(
+            allowed_fast_set(Ipv4Addr::new(80, 4, 4, 1), [0xaa; 20], 1313, 7),
+            allowed_fast_set(ip, [0xaa; 20], 1313, 7)
+        );
+        assert_eq!(allowed_fast_set(ip, [0xaa; 20], 3, 10).len(), 3);
+    }
+
+    #[test]
+    fn our_handshake_advertises_extensions() {
+        let bytes = PeerHandshake::new([1; 20], [2; 20]).to_bytes();
+        assert_eq!(bytes[0], 19);
+        assert_eq!(&bytes[1..20], b"BitTorrent protocol");
+        assert_eq!(bytes[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0]);
+        assert_eq!(bytes[28..48], [1; 20]);
+        assert_eq!(bytes[48..68], [2; 20]);
+    }
+
+    #[test]
+    fn extension_handshakes_from_real_clients() {
+        // libtorrent (qBittorrent) and Transmission, keys they send that we
+        // don't use included
+        // payload, ut_metadata id, metadata_size, client version
+        type Case = (&'static [u8], Option<u8>, Option<i64>, &'static str);
+        let cases: [Case; 2] = [
+            (
+                b"d12:complete_agoi-1e1:md11:lt_donthavei7e10:share_modei8e11:upload_onlyi3e12:ut_holepunchi4e11:ut_metadatai2e6:ut_pexi1ee13:metadata_sizei31235e4:reqqi500e11:upload_onlyi0e1:v17:qBittorrent/4.2.5e",
+                Some(2),
+                Some(31235),
+                "qBittorrent/4.2.5",
+            ),
+            (
+                b"d1:ei1e4:ipv616:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x011:md11:ut_metadatai3e6:ut_pexi1ee1:pi51413e4:reqqi512e1:v17:Transmission 3.00e",
+                Some(3),
+                None,
+                "Transmission 3.00",
+            ),
+        ];
+        for (payload, ut_metadata, metadata_size, version) in cases {
+            let hs: ExtensionHandshake = serde_bencode::from_bytes(payload).unwrap();
+            assert_eq!(hs.ut_metadata(), ut_metadata);
+            assert_eq!(hs.metadata_size, metadata_size);
+            assert_eq!(hs.client_version().as_deref(), Some(version));
+
+            let framed = [&[20, 0][..], payload].concat();
+            let msg = PeerMessage::from_bytes(&framed).unwrap();
+            assert_eq!(msg.to_bytes(), framed);
+        }
+    }
+
+    #[test]
+    fn extension_handshake_yourip() {
+        // libtorrent sends our address as 4 or 16 raw bytes
+        let hs: ExtensionHandshake =
+            serde_bencode::from_bytes(b"d1:md11:ut_metadatai2ee6:yourip4:\xcb\x00\x71\x07e")
+                .unwrap();
+        assert_eq!(hs.your_ip(), Some("203.0.113.7".parse().unwrap()));
+
+        let mut v6 = b"d6:yourip16:".to_vec();
+        v6.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        v6.push(b'e');
+        let hs: ExtensionHandshake = serde_bencode::from_bytes(&v6).unwrap();
+        assert_eq!(hs.your_ip(), Some("2001:db8::1".parse().unwrap()));
+
+        let hs: ExtensionHandshake = serde_bencode::from_bytes(b"d6:yourip3:abce").unwrap();
+        assert_eq!(hs.your_ip(), None);
+    }
+
+    #[test]
+    fn our_extension_handshake_encodes_canonically() {
+        let hs = ExtensionHandshake {
+            m: BTreeMap::from([("ut_metadata".to_string(), UT_METADATA_ID.into())]),
+            metadata_size: Some(31235),
+            yourip: None,
+            v: None,
+        };
+        assert_eq!(
+            serde_bencode::to_bytes(&hs).unwrap(),
+            b"d1:md11:ut_metadatai1ee13:metadata_sizei31235ee"
+        );
+    }
+}
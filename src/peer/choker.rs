@@ -0,0 +1,191 @@
+// tit-for-tat choking: the peers we've recently sent the most to get our
+// upload slots, plus one optimistic slot that rotates so newcomers get a chance
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use crate::utils::random_u32;
+
+// regular upload slots, not counting the optimistic one
+pub const UPLOAD_SLOTS: usize = 4;
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// the optimistic unchoke moves on every third rechoke, i.e. every 30 seconds
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+#[derive(Default)]
+struct Peer {
+    interested: bool,
+    // bytes counted toward this peer since the last rechoke
+    bytes: u64,
+}
+
+pub struct Choker {
+    slots: usize,
+    peers: HashMap<SocketAddr, Peer>,
+    optimistic: Option<SocketAddr>,
+    rounds: u32,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Choker {
+            slots,
+            peers: HashMap::new(),
+            optimistic: None,
+            rounds: 0,
+        }
+    }
+
+    pub fn add(&mut self, addr: SocketAddr) {
+        self.peers.entry(addr).or_default();
+    }
+
+    pub fn remove(&mut self, addr: SocketAddr) {
+        self.peers.remove(&addr);
+        if self.optimistic == Some(addr) {
+            self.optimistic = None;
+        }
+    }
+
+    pub fn set_interested(&mut self, addr: SocketAddr, interested: bool) {
+        self.peers.entry(addr).or_default().interested = interested;
+    }
+
+    // counts bytes we sent a peer toward its share of the slots. only seeding
+    // serves requests, so downloads have nothing for a choker to decide
+    pub fn record(&mut self, addr: SocketAddr, bytes: u64) {
+        self.peers.entry(addr).or_default().bytes += bytes;
+    }
+
+    // picks who to unchoke for the next RECHOKE_INTERVAL and starts counting
+    // afresh; everyone not in the returned set should be choked
+    pub fn rechoke(&mut self) -> HashSet<SocketAddr> {
+        let mut ranked: Vec<(SocketAddr, u64)> = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.interested)
+            .map(|(addr, p)| (*addr, p.bytes))
+            .collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1));
+        let mut unchoked: HashSet<SocketAddr> = ranked
+            .iter()
+            .take(self.slots)
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        let still_useful = self
+            .optimistic
+            .filter(|o| self.peers.get(o).is_some_and(|p| p.interested))
+            .filter(|o| !unchoked.contains(o));
+        self.optimistic = match still_useful {
+            Some(o) if self.rounds % OPTIMISTIC_ROUNDS != 0 => Some(o),
+            _ => {
+                let candidates: Vec<SocketAddr> = ranked
+                    .iter()
+                    .map(|(addr, _)| *addr)
+                    .filter(|addr| !unchoked.contains(addr))
+                    .collect();
+                if candidates.is_empty() {
+                    None
+                } else {
+                    Some(candidates[random_u32() as usize % candidates.len()])
+                }
+            }
+        };
+        unchoked.extend(self.optimistic);
+
+        self.rounds += 1;
+        for p in self.peers.values_mut() {
+            p.bytes = 0;
+        }
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    #[test]
+    fn busiest_interested_peers_win() {
+        let mut choker = Choker::new(2);
+        for (n, bytes) in [(1, 100), (2, 300), (3, 200)] {
+            choker.set_interested(addr(n), true);
+            choker.record(addr(n), bytes);
+        }
+        choker.add(addr(4));
+        choker.record(addr(4), 1000);
+
+        let unchoked = choker.rechoke();
+        // the two regular slots, plus the only other interested peer as the
+        // optimistic one; the uninterested peer stays choked however much it
+        // got from us
+        assert_eq!(unchoked, HashSet::from([addr(2), addr(3), addr(1)]));
+
+        // counts start afresh each round
+        choker.record(addr(1), 500);
+        choker.record(addr(3), 50);
+        let unchoked = choker.rechoke();
+        assert!(unchoked.contains(&addr(1)));
+        assert!(!unchoked.contains(&addr(4)));
+    }
+
+    #[test]
+    fn nobody_interested_nobody_unchoked() {
+        let mut choker = Choker::new(2);
+        choker.add(addr(1));
+        choker.record(addr(1), 100);
+        choker.set_interested(addr(2), true);
+        choker.set_interested(addr(2), false);
+        assert!(choker.rechoke().is_empty());
+    }
+
+    // the optimistic pick among `peers` interested peers that never get a
+    // regular slot, for each of `rounds` rechokes
+    fn optimistic_picks(peers: u8, rounds: usize) -> Vec<SocketAddr> {
+        let mut choker = Choker::new(0);
+        for n in 1..=peers {
+            choker.set_interested(addr(n), true);
+        }
+        (0..rounds)
+            .map(|_| {
+                let unchoked = choker.rechoke();
+                assert_eq!(unchoked.len(), 1);
+                *unchoked.iter().next().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn optimistic_slot_rotates_every_third_round() {
+        let mut moved = false;
+        for _ in 0..50 {
+            let picks = optimistic_picks(8, 7);
+            // held for OPTIMISTIC_ROUNDS rechokes at a time
+            assert_eq!(picks[0], picks[1]);
+            assert_eq!(picks[0], picks[2]);
+            assert_eq!(picks[3], picks[4]);
+            assert_eq!(picks[3], picks[5]);
+            // and then picked afresh, which may land on the same peer again
+            moved |= picks[3] != picks[0] || picks[6] != picks[3];
+        }
+        assert!(moved);
+    }
+
+    #[test]
+    fn optimistic_peer_replaced_when_it_loses_interest() {
+        let mut choker = Choker::new(0);
+        choker.set_interested(addr(1), true);
+        choker.set_interested(addr(2), true);
+        let first = *choker.rechoke().iter().next().unwrap();
+        choker.set_interested(first, false);
+        let second = *choker.rechoke().iter().next().unwrap();
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,106 @@
+// replays a captured byte stream from one side of a peer connection through
+// our codec, to catch messages we'd misread or couldn't reproduce exactly
+use super::{PeerHandshake, PeerMessage};
+use crate::error::{Error, Result};
+
+// decodes every frame in `stream` (optionally led by a handshake) and checks
+// that re-encoding it gives back the same bytes. returns each frame's offset
+// and what it decoded to, or an error naming the first frame that didn't
+// survive the round trip.
+pub fn replay(stream: &[u8]) -> Result<Vec<(usize, String)>> {
+    let mut frames = vec![];
+    let mut pos = 0;
+
+    // length prefixes start with a zero byte for any sane message size, so a
+    // leading 19 can only be the handshake's protocol string length
+    if stream.first() == Some(&19) {
+        let raw = stream
+            .get(..68)
+            .ok_or_else(|| bad(0, "truncated handshake".into()))?;
+        let hs = PeerHandshake::from_bytes(raw);
+        if hs.to_bytes() != raw {
+            return Err(bad(0, "handshake re-encodes differently".into()));
+        }
+        frames.push((
+            0,
+            format!(
+                "Handshake {{ reserved {}, info hash {}, peer id {} }}",
+                hex::encode(hs.reserved),
+                hex::encode(hs.info_hash),
+                String::from_utf8_lossy(&hs.peer_id)
+            ),
+        ));
+        pos = 68;
+    }
+
+    while pos < stream.len() {
+        let prefix = stream
+            .get(pos..pos + 4)
+            .ok_or_else(|| bad(pos, "truncated length prefix".into()))?;
+        let len = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+        if len == 0 {
+            frames.push((pos, "KeepAlive".to_string()));
+            pos += 4;
+            continue;
+        }
+        let body = stream
+            .get(pos + 4..pos + 4 + len)
+            .ok_or_else(|| bad(pos, format!("frame of {} bytes runs past the end", len)))?;
+        let msg = PeerMessage::from_bytes(body).map_err(|e| bad(pos, e.to_string()))?;
+        if msg.to_bytes() != body {
+            return Err(bad(pos, format!("{:?} re-encodes differently", msg)));
+        }
+        frames.push((pos, format!("{:?}", msg)));
+        pos += 4 + len;
+    }
+    Ok(frames)
+}
+
+fn bad(offset: usize, msg: String) -> Error {
+    Error::Protocol(format!("at byte {}: {}", offset, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_a_handshake_and_messages() {
+        let mut stream = vec![19];
+        stream.extend_from_slice(b"BitTorrent protocol");
+        stream.extend_from_slice(&[0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        stream.extend_from_slice(&[0xaa; 20]);
+        stream.extend_from_slice(b"-qB4250-abcdefghijkl");
+        stream.extend_from_slice(&[0, 0, 0, 3, 5, 0xff, 0xe0]); // bitfield
+        stream.extend_from_slice(&[0, 0, 0, 0]); // keepalive
+        stream.extend_from_slice(&[0, 0, 0, 1, 1]); // unchoke
+
+        let frames = replay(&stream).unwrap();
+        let offsets: Vec<usize> = frames.iter().map(|(o, _)| *o).collect();
+        assert_eq!(offsets, [0, 68, 75, 79]);
+        assert!(frames[0].1.contains("-qB4250-abcdefghijkl"));
+        assert_eq!(frames[2].1, "KeepAlive");
+    }
+
+    #[test]
+    fn rejects_frames_that_dont_round_trip() {
+        // a request with a stray trailing byte decodes, but not losslessly
+        let stream = [0, 0, 0, 14, 6, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x40, 0, 0xff];
+        assert!(matches!(replay(&stream), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        for stream in [
+            &[0, 0, 0][..],
+            &[0, 0, 0, 5, 4, 0, 0][..],
+            &[19, 66, 105][..],
+        ] {
+            assert!(
+                matches!(replay(stream), Err(Error::Protocol(_))),
+                "{:?}",
+                stream
+            );
+        }
+    }
+}
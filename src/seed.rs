@@ -0,0 +1,275 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{watch, Mutex},
+    time::timeout,
+};
+
+use crate::{
+    download::{Reannouncer, Storage},
+    metainfo::{InfoDict, Metainfo},
+    peer::{
+        allowed_fast_set,
+        choker::{Choker, RECHOKE_INTERVAL, UPLOAD_SLOTS},
+        PeerMessage, PeerState,
+    },
+    tracker,
+};
+
+// requests bigger than this are refused; real clients ask for 16KiB blocks
+const MAX_BLOCK_LEN: u32 = 128 * 1024;
+const FINAL_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+// pieces a peer using the fast extension may fetch while choked (BEP 6)
+const ALLOWED_FAST: usize = 10;
+
+// what the pieces on disk are worth to other peers
+struct Have {
+    bitfield: Vec<u8>,
+    // bytes of pieces that are missing or don't match their hash
+    missing: u64,
+}
+
+impl Have {
+    fn has_piece(&self, index: u32) -> bool {
+        self.bitfield
+            .get(index as usize / 8)
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+}
+
+// what every connection task needs
+struct Shared {
+    info: InfoDict,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    storage: Mutex<Storage>,
+    have: Have,
+    uploaded: AtomicU64,
+    choker: std::sync::Mutex<Choker>,
+}
+
+// which pieces on disk check out
+async fn check_pieces(info: &InfoDict, storage: &mut Storage) -> anyhow::Result<Have> {
+    let good = crate::download::check_pieces(info, storage).await?;
+    let mut have = Have {
+        bitfield: vec![0; good.len().div_ceil(8)],
+        missing: 0,
+    };
+    for (index, ok) in good.into_iter().enumerate() {
+        if ok {
+            have.bitfield[index / 8] |= 0x80 >> (index % 8);
+        } else {
+            have.missing += info.piece_size(index as u32) as u64;
+        }
+    }
+    Ok(have)
+}
+
+// listens on the port we announce, serving the pieces in `storage` that
+// check out to whoever the choker unchokes, until Ctrl-C. the trackers hear
+// about how much we've uploaded on their schedule, and "stopped" at the end.
+// returns the number of bytes uploaded.
+pub async fn seed(
+    metainf: &Metainfo,
+    mut storage: Storage,
+    peer_id: [u8; 20],
+    bind_addr: Option<IpAddr>,
+) -> anyhow::Result<u64> {
+    let have = check_pieces(&metainf.info, &mut storage).await?;
+    if have.missing == metainf.info.length() {
+        anyhow::bail!("none of the torrent's pieces are on disk");
+    }
+    if have.missing > 0 {
+        eprintln!(
+            "warning: {} bytes of pieces are missing or corrupt and won't be served",
+            have.missing
+        );
+    }
+
+    let local = SocketAddr::new(
+        bind_addr.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        tracker::LISTEN_PORT,
+    );
+    let listener = TcpListener::bind(local)
+        .await
+        .with_context(|| format!("failed to listen on {}", local))?;
+    eprintln!("listening for peers on {}", local);
+
+    let mut progress = tracker::Progress {
+        downloaded: 0,
+        uploaded: 0,
+        left: have.missing,
+        event: Some(tracker::AnnounceEvent::Started),
+        ..Default::default()
+    };
+    let (_, mut reannouncer) =
+        Reannouncer::start(metainf, peer_id, bind_addr, progress, Default::default()).await?;
+    progress.event = None;
+
+    let shared = Arc::new(Shared {
+        info: metainf.info.clone(),
+        info_hash: metainf.info_hash()?,
+        peer_id,
+        storage: Mutex::new(storage),
+        have,
+        uploaded: AtomicU64::new(0),
+        choker: std::sync::Mutex::new(Choker::new(UPLOAD_SLOTS)),
+    });
+    // the choker's latest decision, for every connection to act on
+    let (unchoked_tx, unchoked_rx) = watch::channel(HashSet::new());
+    let mut rechoke = tokio::time::interval(RECHOKE_INTERVAL);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (conn, remote) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("failed to accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                let shared = shared.clone();
+                let unchoked = unchoked_rx.clone();
+                tokio::spawn(async move {
+                    shared.choker.lock().expect("choker lock poisoned").add(remote);
+                    if let Err(e) = serve(conn, remote, &shared, unchoked).await {
+                        eprintln!("dropping peer {}: {:#}", remote, e);
+                    }
+                    shared.choker.lock().expect("choker lock poisoned").remove(remote);
+                });
+            }
+            _ = rechoke.tick() => {
+                let unchoked = shared.choker.lock().expect("choker lock poisoned").rechoke();
+                // the receivers live as long as the connections; with none
+                // connected there's nobody to tell
+                let _ = unchoked_tx.send(unchoked);
+            }
+            _ = tokio::time::sleep_until(reannouncer.due()) => {
+                progress.uploaded = shared.uploaded.load(Ordering::Relaxed);
+                if let Err(e) = reannouncer.announce(progress).await {
+                    eprintln!("re-announce failed: {:#}", e);
+                }
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    let stopped = tracker::Progress {
+        uploaded: shared.uploaded.load(Ordering::Relaxed),
+        event: Some(tracker::AnnounceEvent::Stopped),
+        ..progress
+    };
+    match timeout(FINAL_ANNOUNCE_TIMEOUT, reannouncer.announce(stopped)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => eprintln!("announcing Stopped failed: {:#}", e),
+        Err(_) => eprintln!("announcing Stopped timed out"),
+    }
+    Ok(stopped.uploaded)
+}
+
+// one incoming peer: handshake, tell it what we have, and answer its
+// requests for as long as it stays and the choker lets it
+async fn serve(
+    conn: TcpStream,
+    remote: SocketAddr,
+    shared: &Shared,
+    mut unchoked: watch::Receiver<HashSet<SocketAddr>>,
+) -> anyhow::Result<()> {
+    let info = &shared.info;
+    let piece_length = info.piece_length() as u64;
+    let mut peer =
+        PeerState::accept(conn, remote, info.clone(), shared.info_hash, shared.peer_id).await?;
+    eprintln!("peer {} connected", remote);
+    peer.send_bitfield(shared.have.bitfield.clone()).await?;
+    // BEP 6 only defines the set for IPv4 peers
+    let allowed_fast: Vec<u32> = match remote.ip().to_canonical() {
+        IpAddr::V4(ip) if peer.fast() => {
+            allowed_fast_set(ip, shared.info_hash, info.num_pieces() as u32, ALLOWED_FAST)
+                .into_iter()
+                .filter(|index| shared.have.has_piece(*index))
+                .collect()
+        }
+        _ => vec![],
+    };
+    peer.send_allowed_fast(&allowed_fast).await?;
+
+    loop {
+        let msgs = tokio::select! {
+            msgs = peer.poll() => msgs?,
+            changed = unchoked.changed() => {
+                changed?;
+                let choke = !unchoked.borrow().contains(&remote);
+                peer.set_choked(choke).await?;
+                continue;
+            }
+        };
+        for m in msgs {
+            match m {
+                PeerMessage::Interested {} | PeerMessage::NotInterested {} => {
+                    shared
+                        .choker
+                        .lock()
+                        .expect("choker lock poisoned")
+                        .set_interested(remote, peer.interested());
+                }
+                PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    // requests that arrive while choked are dropped, as the
+                    // peer knows to expect, unless the fast extension is in
+                    // use: then they're rejected, or served if allowed fast
+                    if peer.choked() && !allowed_fast.contains(&index) {
+                        if peer.fast() {
+                            peer.send_reject(index, begin, length).await?;
+                        }
+                        continue;
+                    }
+                    let in_piece = shared.have.has_piece(index)
+                        && length <= MAX_BLOCK_LEN
+                        && begin
+                            .checked_add(length)
+                            .is_some_and(|end| end <= info.piece_size(index));
+                    if !in_piece {
+                        anyhow::bail!(
+                            "asked for {} bytes at {} of piece {}, which we can't serve",
+                            length,
+                            begin,
+                            index
+                        );
+                    }
+                    let block = shared
+                        .storage
+                        .lock()
+                        .await
+                        .read_at(index as u64 * piece_length + begin as u64, length as usize)
+                        .await
+                        .with_context(|| format!("error reading piece {}", index))?;
+                    let sent = peer.send_block(index, begin, block).await? as u64;
+                    shared.uploaded.fetch_add(sent, Ordering::Relaxed);
+                    // while seeding, reciprocation is measured by what the
+                    // peer takes from us
+                    shared
+                        .choker
+                        .lock()
+                        .expect("choker lock poisoned")
+                        .record(remote, sent);
+                }
+                // requests are answered as soon as they're read, so by the
+                // time a Cancel arrives there's nothing left to cancel
+                _ => {}
+            }
+        }
+    }
+}
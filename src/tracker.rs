@@ -1,26 +1,68 @@
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
 
-use anyhow::{anyhow, Context};
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 
+use crate::error::{Detailed, Error};
+
+mod udp;
+
+pub const LISTEN_PORT: u16 = 6881;
+
 #[derive(Serialize, Deserialize)]
 struct TrackerError {
     #[serde(rename = "failure reason")]
     failure_reason: String,
 }
 
+// trackers that ignore compact=1 send a list of dicts instead of a string
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PeerList {
+    Compact(ByteBuf),
+    Dicts(Vec<PeerDict>),
+}
+
+impl Default for PeerList {
+    fn default() -> Self {
+        PeerList::Compact(ByteBuf::new())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerDict {
+    #[serde(rename = "peer id")]
+    #[serde(default)]
+    peer_id: Option<ByteBuf>,
+    // an IPv4 or IPv6 address in text form, or sometimes a DNS name
+    ip: String,
+    port: u16,
+}
+
 #[derive(Serialize, Deserialize)]
 struct TrackerPeers {
     interval: u64,
     #[serde(default)]
-    peers: ByteBuf,
+    peers: PeerList,
     complete: u64,
     incomplete: u64,
     #[serde(rename = "min interval")]
-    min_interval: u64,
+    #[serde(default)]
+    min_interval: Option<u64>,
     #[serde(default)]
     peers6: ByteBuf,
+    #[serde(rename = "external ip")]
+    #[serde(default)]
+    external_ip: Option<ByteBuf>,
+    #[serde(rename = "tracker id")]
+    #[serde(default)]
+    tracker_id: Option<ByteBuf>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -30,6 +72,254 @@ enum TrackerResponse {
     Success(TrackerPeers),
 }
 
+pub struct Announce {
+    pub peers: Vec<SocketAddr>,
+    // how long the tracker wants us to wait before announcing again, and the
+    // shortest wait it will put up with
+    pub interval: Duration,
+    pub min_interval: Option<Duration>,
+    // our address as seen by the tracker, if it told us
+    pub external_ip: Option<IpAddr>,
+    pub discarded: DiscardedPeers,
+    // to be echoed back on later announces to the same tracker
+    pub tracker_id: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+// what we report about our transfer on each announce
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Progress {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub left: u64,
+    // bytes thrown away for failing their hash check, and bytes of pieces
+    // that another peer had already delivered; both already count towards
+    // `downloaded`
+    pub corrupt: u64,
+    pub redundant: u64,
+    // absent for the regular announces in between
+    pub event: Option<AnnounceEvent>,
+}
+
+impl Progress {
+    // nothing transferred yet, all `length` bytes still to get
+    pub fn starting(length: u64) -> Self {
+        Progress {
+            left: length,
+            ..Default::default()
+        }
+    }
+
+    // for a magnet link, whose length isn't known until the metadata is.
+    // there's no way to say "unknown" in an announce, so report a single
+    // block left: any nonzero `left` tells the tracker we're still
+    // downloading rather than seeding
+    pub fn before_metadata() -> Self {
+        Progress {
+            left: 16 * 1024,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct DiscardedPeers {
+    pub duplicate: usize,
+    pub ourselves: usize,
+    pub port_zero: usize,
+    pub bogus: usize,
+}
+
+impl DiscardedPeers {
+    pub fn total(&self) -> usize {
+        self.duplicate + self.ourselves + self.port_zero + self.bogus
+    }
+}
+
+// addresses no real peer can be listening on
+fn is_bogus(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            v4.is_unspecified() || v4.is_multicast() || v4.is_broadcast() || v4.octets()[0] >= 240
+        }
+        IpAddr::V6(v6) => v6.is_unspecified() || v6.is_multicast(),
+    }
+}
+
+// drops duplicates, our own address, port 0 and bogus addresses, keeping
+// the tracker's order for the rest
+pub fn filter_peers(
+    peers: Vec<SocketAddr>,
+    ourselves: Option<SocketAddr>,
+) -> (Vec<SocketAddr>, DiscardedPeers) {
+    let mut discarded = DiscardedPeers::default();
+    let mut kept: Vec<SocketAddr> = Vec::with_capacity(peers.len());
+    for p in peers {
+        if p.port() == 0 {
+            discarded.port_zero += 1;
+        } else if is_bogus(p.ip()) {
+            discarded.bogus += 1;
+        } else if Some(p) == ourselves {
+            discarded.ourselves += 1;
+        } else if kept.contains(&p) {
+            discarded.duplicate += 1;
+        } else {
+            kept.push(p);
+        }
+    }
+    (kept, discarded)
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("tracker does not support scrape: {0}")]
+pub struct ScrapeUnsupported(pub String);
+
+// derives the scrape URL per the convention in BEP 48: the last path segment
+// of an http(s) announce URL must start with "announce", which is swapped for
+// "scrape". udp trackers scrape over the same endpoint they announce on.
+pub fn scrape_url(announce: &str) -> Result<String, ScrapeUnsupported> {
+    if announce.starts_with("udp://") {
+        return Ok(announce.to_string());
+    }
+    if !announce.starts_with("http://") && !announce.starts_with("https://") {
+        return Err(ScrapeUnsupported(announce.to_string()));
+    }
+
+    let (base, query) = match announce.find('?') {
+        Some(q) => announce.split_at(q),
+        None => (announce, ""),
+    };
+    let last_slash = base
+        .rfind('/')
+        .ok_or_else(|| ScrapeUnsupported(announce.to_string()))?;
+    let (prefix, last_segment) = base.split_at(last_slash + 1);
+    match last_segment.strip_prefix("announce") {
+        Some(rest) if !prefix.ends_with("//") => Ok(format!("{}scrape{}{}", prefix, rest, query)),
+        _ => Err(ScrapeUnsupported(announce.to_string())),
+    }
+}
+
+// BEP 12 tiers of trackers, tried in order. trackers are shuffled within
+// each tier once up front, and one that answers is moved to the front of its
+// tier so it's tried first next time.
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+    // tracker URL => the 'tracker id' it last gave us, sent back to it on
+    // every later announce
+    tracker_ids: BTreeMap<String, Vec<u8>>,
+}
+
+impl TrackerTiers {
+    // without an announce-list, the lone `announce` URL is the only tier
+    pub fn new(announce: &str, announce_list: &[Vec<String>]) -> Self {
+        let mut tiers: Vec<Vec<String>> = announce_list
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        if tiers.is_empty() {
+            tiers.push(vec![announce.to_string()]);
+        }
+        for tier in tiers.iter_mut() {
+            // Fisher-Yates
+            for i in (1..tier.len()).rev() {
+                let j = crate::utils::random_u32() as usize % (i + 1);
+                tier.swap(i, j);
+            }
+        }
+        TrackerTiers {
+            tiers,
+            tracker_ids: BTreeMap::new(),
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn tracker_ids(&self) -> &BTreeMap<String, Vec<u8>> {
+        &self.tracker_ids
+    }
+
+    // ids remembered from an earlier session, e.g. in resume data
+    pub fn set_tracker_ids(&mut self, ids: BTreeMap<String, Vec<u8>>) {
+        self.tracker_ids = ids;
+    }
+
+    // announces to each tracker in turn until one succeeds, returning its URL
+    // along with what it said
+    pub async fn announce(
+        &mut self,
+        progress: Progress,
+        infohash: [u8; 20],
+        my_peer_id: [u8; 20],
+        local_addr: Option<IpAddr>,
+    ) -> anyhow::Result<(String, Announce)> {
+        let mut last_err = None;
+        for t in 0..self.tiers.len() {
+            for i in 0..self.tiers[t].len() {
+                let tracker_addr = &self.tiers[t][i];
+                eprintln!("fetching peers from tracker at {}", tracker_addr);
+                let tracker_id = self.tracker_ids.get(tracker_addr).map(Vec::as_slice);
+                match announce(
+                    tracker_addr,
+                    progress,
+                    infohash,
+                    my_peer_id,
+                    local_addr,
+                    tracker_id,
+                )
+                .await
+                {
+                    Ok(announced) => {
+                        let url = self.promote(t, i);
+                        if let Some(id) = &announced.tracker_id {
+                            self.tracker_ids.insert(url.clone(), id.clone());
+                        }
+                        return Ok((url, announced));
+                    }
+                    Err(e) => {
+                        let url = &self.tiers[t][i];
+                        eprintln!("tracker {} failed: {:#}", url, e);
+                        last_err = Some(
+                            Detailed::new(e, format!("tracker {} failed", url))
+                                .field("url", url.as_str())
+                                .into(),
+                        );
+                    }
+                }
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| anyhow::anyhow!("no trackers to announce to"))
+            .context("every tracker failed"))
+    }
+
+    // BEP 12: a tracker that answered moves to the front of its tier, so it's
+    // tried first next time; returns its URL
+    fn promote(&mut self, tier: usize, i: usize) -> String {
+        let url = self.tiers[tier].remove(i);
+        self.tiers[tier].insert(0, url.clone());
+        url
+    }
+}
+
 fn urlenc<B: AsRef<[u8]>>(bytes: B) -> String {
     bytes
         .as_ref()
@@ -43,83 +333,523 @@ fn urlenc<B: AsRef<[u8]>>(bytes: B) -> String {
         .collect::<String>()
 }
 
-pub async fn announce(
+// trackers that refused compact=1 this session, which get compact=0 from then on
+static NON_COMPACT: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+fn wants_compact(tracker_addr: &str) -> bool {
+    !NON_COMPACT
+        .lock()
+        .expect("tracker preferences lock poisoned")
+        .contains(tracker_addr)
+}
+
+fn refuses_compact(tracker_addr: &str) {
+    NON_COMPACT
+        .lock()
+        .expect("tracker preferences lock poisoned")
+        .insert(tracker_addr.to_string());
+}
+
+// the URL an HTTP announce is sent to, query string and all
+pub fn announce_url(
     tracker_addr: &str,
-    left: u32,
+    progress: Progress,
     infohash: [u8; 20],
     my_peer_id: [u8; 20],
-) -> anyhow::Result<Vec<SocketAddr>> {
+    local_addr: Option<IpAddr>,
+    tracker_id: Option<&[u8]>,
+) -> anyhow::Result<reqwest::Url> {
     let ih_urlenc = urlenc(infohash);
     let id_urlenc = urlenc(my_peer_id);
 
-    let tracker_client = reqwest::Client::new();
-    let mut req = tracker_client
-        .get(tracker_addr)
-        .query(&[
-            ("compact", "1"),
-            ("left", &left.to_string()),
-            ("port", "6881"),
-            ("uploaded", "0"),
-            ("downloaded", "0"),
-        ])
-        .build()
+    let mut url = reqwest::Url::parse(tracker_addr)
         .context("failed building tracker HTTP announcement request")?;
-    let q = req
-        .url()
-        .query()
-        .expect("query parameters were not created");
-    let newq = q.to_owned() + "&info_hash=" + &ih_urlenc + "&peer_id=" + &id_urlenc;
-    req.url_mut().set_query(Some(&newq));
-
-    //eprintln!("request: {:?}", req);
-    let res = tracker_client
-        .execute(req)
-        .await
-        .expect("failed to get from tracker");
-    let body = res
-        .bytes()
-        .await
-        .expect("could not read response from tracker")
-        .to_vec();
+    let compact = if wants_compact(tracker_addr) {
+        "1"
+    } else {
+        "0"
+    };
+    url.query_pairs_mut().extend_pairs([
+        ("compact", compact),
+        ("left", &progress.left.to_string()),
+        ("port", &LISTEN_PORT.to_string()),
+        ("uploaded", &progress.uploaded.to_string()),
+        ("downloaded", &progress.downloaded.to_string()),
+        ("corrupt", &progress.corrupt.to_string()),
+        ("redundant", &progress.redundant.to_string()),
+    ]);
+    let q = url.query().unwrap_or_default();
+    let mut newq = q.to_owned() + "&info_hash=" + &ih_urlenc + "&peer_id=" + &id_urlenc;
+    if let Some(tid) = tracker_id {
+        newq = newq + "&trackerid=" + &urlenc(tid);
+    }
+    if let Some(event) = progress.event {
+        newq = newq + "&event=" + event.as_str();
+    }
+    // BEP 7: a tracker reached over v4 can't see our v6 address otherwise
+    if let Some(IpAddr::V6(v6)) = local_addr.filter(|ip| !ip.is_unspecified()) {
+        newq = newq + "&ipv6=" + &urlenc(v6.to_string());
+    }
+    url.set_query(Some(&newq));
+    Ok(url)
+}
+
+pub async fn announce(
+    tracker_addr: &str,
+    progress: Progress,
+    infohash: [u8; 20],
+    my_peer_id: [u8; 20],
+    local_addr: Option<IpAddr>,
+    tracker_id: Option<&[u8]>,
+) -> anyhow::Result<Announce> {
+    if tracker_addr.starts_with("udp://") {
+        return udp::announce(tracker_addr, progress, infohash, my_peer_id, local_addr).await;
+    }
+    let tracker_client = reqwest::Client::builder()
+        .local_address(local_addr)
+        .build()
+        .context("failed building tracker HTTP client")?;
+
+    let body = loop {
+        let compact = wants_compact(tracker_addr);
+        let url = announce_url(
+            tracker_addr,
+            progress,
+            infohash,
+            my_peer_id,
+            local_addr,
+            tracker_id,
+        )?;
+        let req = tracker_client
+            .get(url)
+            .build()
+            .context("failed building tracker HTTP announcement request")?;
+
+        //eprintln!("request: {:?}", req);
+        let res = tracker_client.execute(req).await.map_err(Error::Http)?;
+        let body = res.bytes().await.map_err(Error::Http)?.to_vec();
+        if compact && rejects_compact(&body) {
+            eprintln!(
+                "tracker {} refused a compact peer list, asking for the dictionary form",
+                tracker_addr
+            );
+            refuses_compact(tracker_addr);
+            continue;
+        }
+        break body;
+    };
+    let body = match crate::bencode::normalize(&body, crate::bencode::KeyPolicy::KeepLast) {
+        Ok((canonical, fixes)) => {
+            for fix in fixes {
+                eprintln!("warning: tracker response has {}", fix);
+            }
+            canonical
+        }
+        Err(_) => body, // let the real decode below report what's wrong
+    };
     //eprintln!("got a response: {}", String::from_utf8_lossy(&body));
-    match serde_bencode::from_bytes(&body) {
-        Ok(TrackerResponse::Error(e)) => Err(anyhow!(
-            "tracker responded with error: {}",
-            e.failure_reason
-        )),
-        Ok(TrackerResponse::Success(r)) => Ok(r
-            .peers
-            .chunks(6)
-            .map(|peer| {
-                let mut ipbytes = [0; 4];
-                ipbytes.copy_from_slice(&peer[0..4]);
-                let mut skbytes = [0u8; 2];
-                skbytes.copy_from_slice(&peer[4..6]);
-                SocketAddr::new(
-                    std::net::IpAddr::V4(Ipv4Addr::from(ipbytes)),
-                    u16::from_be_bytes(skbytes),
-                )
+    match crate::bencode::decode(&body) {
+        Ok(TrackerResponse::Error(e)) => Err(Error::Tracker(e.failure_reason).into()),
+        Ok(TrackerResponse::Success(r)) => {
+            let external_ip = r.external_ip.as_ref().and_then(|ip| compact_ip(ip));
+            let (mut peers, unparsed) = match &r.peers {
+                PeerList::Compact(v4) => (compact_peers(v4, &[]), 0),
+                PeerList::Dicts(dicts) => dict_peers(dicts),
+            };
+            peers.extend(compact_peers(&[], &r.peers6));
+            let (peers, mut discarded) = filter_peers(
+                peers,
+                external_ip.map(|ip| SocketAddr::new(ip, LISTEN_PORT)),
+            );
+            discarded.bogus += unparsed;
+            Ok(Announce {
+                peers,
+                interval: Duration::from_secs(r.interval),
+                min_interval: r.min_interval.map(Duration::from_secs),
+                external_ip,
+                discarded,
+                tracker_id: r.tracker_id.map(ByteBuf::into_vec),
             })
-            .chain(r.peers6.chunks(18).map(|peer| {
-                let mut ipbytes = [0; 16];
-                ipbytes.copy_from_slice(&peer[0..16]);
-                let mut skbytes = [0u8; 2];
-                skbytes.copy_from_slice(&peer[16..18]);
-                SocketAddr::new(
-                    std::net::IpAddr::V6(Ipv6Addr::from(ipbytes)),
-                    u16::from_be_bytes(skbytes),
-                )
-            }))
-            .collect()),
+        }
         Err(e) => {
-            eprintln!(
-                "error reading tracker data, data as json:\n{}",
-                crate::utils::convert_bencode_to_json(
-                    serde_bencode::from_bytes(&body).expect("could not deserialize as bencode")
-                )
-                .expect("invalid conversion")
+            if let Ok(json) =
+                crate::bencode::decode(&body).and_then(crate::utils::convert_bencode_to_json)
+            {
+                eprintln!("error reading tracker data, data as json:\n{}", json);
+            }
+            Err(Error::Tracker(format!("undecodable response: {}", e)).into())
+        }
+    }
+}
+
+// whether a response is a failure blaming compact=1, which trackers that only
+// do the dictionary model send
+fn rejects_compact(body: &[u8]) -> bool {
+    match crate::bencode::decode(body) {
+        Ok(TrackerResponse::Error(e)) => e.failure_reason.to_lowercase().contains("compact"),
+        _ => false,
+    }
+}
+
+// decodes the compact peer formats: 6 bytes per IPv4 peer and 18 per IPv6
+// peer, address then port, both big-endian
+fn compact_peers(v4: &[u8], v6: &[u8]) -> Vec<SocketAddr> {
+    v4.chunks_exact(6)
+        .map(|peer| {
+            let mut ipbytes = [0; 4];
+            ipbytes.copy_from_slice(&peer[0..4]);
+            let mut skbytes = [0u8; 2];
+            skbytes.copy_from_slice(&peer[4..6]);
+            SocketAddr::new(
+                std::net::IpAddr::V4(Ipv4Addr::from(ipbytes)),
+                u16::from_be_bytes(skbytes),
+            )
+        })
+        .chain(v6.chunks_exact(18).map(|peer| {
+            let mut ipbytes = [0; 16];
+            ipbytes.copy_from_slice(&peer[0..16]);
+            let mut skbytes = [0u8; 2];
+            skbytes.copy_from_slice(&peer[16..18]);
+            SocketAddr::new(
+                std::net::IpAddr::V6(Ipv6Addr::from(ipbytes)),
+                u16::from_be_bytes(skbytes),
+            )
+        }))
+        .collect()
+}
+
+// the dictionary model of the peer list; returns the peers plus how many had
+// an `ip` that isn't a literal address (we don't resolve hostnames)
+fn dict_peers(dicts: &[PeerDict]) -> (Vec<SocketAddr>, usize) {
+    let peers: Vec<SocketAddr> = dicts
+        .iter()
+        .filter_map(|d| {
+            d.ip.parse::<IpAddr>()
+                .ok()
+                .map(|ip| SocketAddr::new(ip, d.port))
+        })
+        .collect();
+    let unparsed = dicts.len() - peers.len();
+    (peers, unparsed)
+}
+
+// the "external ip" key is a bare 4 or 16 byte address, like the compact peer
+// format; so is the `yourip` of an extension handshake
+pub fn compact_ip(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?))),
+        16 => Some(IpAddr::V6(Ipv6Addr::from(
+            <[u8; 16]>::try_from(bytes).ok()?,
+        ))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(tiers: &[Vec<String>]) -> Vec<Vec<String>> {
+        tiers
+            .iter()
+            .map(|tier| {
+                let mut tier = tier.clone();
+                tier.sort();
+                tier
+            })
+            .collect()
+    }
+
+    fn strings(urls: &[&str]) -> Vec<String> {
+        urls.iter().map(|u| u.to_string()).collect()
+    }
+
+    fn success(body: &[u8]) -> TrackerPeers {
+        match serde_bencode::from_bytes(body).unwrap() {
+            TrackerResponse::Success(r) => r,
+            TrackerResponse::Error(e) => panic!("decoded as a failure: {}", e.failure_reason),
+        }
+    }
+
+    #[test]
+    fn recognizes_compact_refusals() {
+        assert!(rejects_compact(
+            b"d14:failure reason47:This tracker does not support compact responsese"
+        ));
+        assert!(rejects_compact(
+            b"d14:failure reason24:Compact mode unavailablee"
+        ));
+        assert!(!rejects_compact(
+            b"d14:failure reason20:unregistered torrente"
+        ));
+        assert!(!rejects_compact(
+            b"d8:completei0e10:incompletei0e8:intervali900e5:peers0:e"
+        ));
+        assert!(!rejects_compact(b"not bencode"));
+    }
+
+    #[test]
+    fn remembers_trackers_that_refuse_compact() {
+        let refusing = "http://no-compact.example/announce";
+        let other = "http://compact.example/announce";
+        let compact_param = |tracker_addr: &str| {
+            let url = announce_url(
+                tracker_addr,
+                Progress::starting(1),
+                [0; 20],
+                [0; 20],
+                None,
+                None,
+            )
+            .unwrap();
+            url.query_pairs()
+                .find(|(k, _)| k == "compact")
+                .map(|(_, v)| v.into_owned())
+        };
+        assert!(wants_compact(refusing));
+        assert_eq!(compact_param(refusing).as_deref(), Some("1"));
+
+        refuses_compact(refusing);
+        assert!(!wants_compact(refusing));
+        assert_eq!(compact_param(refusing).as_deref(), Some("0"));
+        assert!(wants_compact(other));
+        assert_eq!(compact_param(other).as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn announce_url_percent_encodes_binary_ids() {
+        let mut infohash = [0u8; 20];
+        infohash[..6].copy_from_slice(b"\xd6\x9f a~.");
+        let peer_id = *b"-BR0001-\x00\xff/?&=%+09aZ";
+        let progress = Progress {
+            event: Some(AnnounceEvent::Started),
+            ..Progress::starting(92063)
+        };
+        let url = announce_url(
+            "http://tracker.example/announce?passkey=abc",
+            progress,
+            infohash,
+            peer_id,
+            None,
+            None,
+        )
+        .unwrap();
+        let query = url.query().unwrap();
+        assert!(query.starts_with("passkey=abc&"), "{}", query);
+        assert!(
+            query.contains(&format!("&info_hash=%D6%9F%20a~.{}&", "%00".repeat(14))),
+            "{}",
+            query
+        );
+        assert!(
+            query.contains("&peer_id=-BR0001-%00%FF%2F%3F%26%3D%25%2B09aZ&"),
+            "{}",
+            query
+        );
+        assert!(query.contains("&left=92063&"), "{}", query);
+        assert!(query.ends_with("&event=started"), "{}", query);
+    }
+
+    #[test]
+    fn peer_list_in_compact_model() {
+        let r = success(
+            b"d8:completei1e10:incompletei2e8:intervali900e5:peers12:\x0a\x00\x00\x01\x1a\xe1\xc0\xa8\x01\x02\xc8\xd5e",
+        );
+        let PeerList::Compact(v4) = &r.peers else {
+            panic!("expected a compact peer list");
+        };
+        assert_eq!(
+            compact_peers(v4, &[]),
+            addrs(&["10.0.0.1:6881", "192.168.1.2:51413"])
+        );
+    }
+
+    #[test]
+    fn peer_list_in_dict_model() {
+        let r = success(
+            b"d8:completei1e10:incompletei2e8:intervali900e5:peersl\
+              d2:ip8:10.0.0.17:peer id20:-TR3000-aaaaaaaaaaaa4:porti6881ee\
+              d2:ip11:2001:db8::14:porti51413ee\
+              d2:ip11:example.com4:porti6881ee\
+              ee",
+        );
+        let PeerList::Dicts(dicts) = &r.peers else {
+            panic!("expected a dictionary peer list");
+        };
+        assert_eq!(
+            dicts[0].peer_id.as_ref().map(|id| id.as_slice()),
+            Some(&b"-TR3000-aaaaaaaaaaaa"[..])
+        );
+        let (peers, unparsed) = dict_peers(dicts);
+        assert_eq!(peers, addrs(&["10.0.0.1:6881", "[2001:db8::1]:51413"]));
+        // hostnames aren't resolved
+        assert_eq!(unparsed, 1);
+    }
+
+    #[test]
+    fn tiers_come_from_announce_list() {
+        let list = vec![
+            strings(&[
+                "http://b/announce",
+                "http://a/announce",
+                "http://c/announce",
+            ]),
+            strings(&["udp://d:6969"]),
+        ];
+        let tiers = TrackerTiers::new("http://ignored/announce", &list);
+        // each tier is shuffled, but keeps its members and its place
+        assert_eq!(
+            sorted(tiers.tiers()),
+            [
+                strings(&[
+                    "http://a/announce",
+                    "http://b/announce",
+                    "http://c/announce"
+                ]),
+                strings(&["udp://d:6969"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn tiers_fall_back_to_announce() {
+        let expected = [strings(&["http://a/announce"])];
+        let tiers = TrackerTiers::new("http://a/announce", &[]);
+        assert_eq!(tiers.tiers(), expected);
+        // an announce-list of nothing but empty tiers is no announce-list
+        let tiers = TrackerTiers::new("http://a/announce", &[vec![], vec![]]);
+        assert_eq!(tiers.tiers(), expected);
+    }
+
+    #[test]
+    fn empty_tiers_are_dropped() {
+        let list = vec![vec![], strings(&["http://a/announce"]), vec![]];
+        let tiers = TrackerTiers::new("http://ignored/announce", &list);
+        assert_eq!(tiers.tiers(), [strings(&["http://a/announce"])]);
+    }
+
+    #[test]
+    fn working_tracker_moves_to_front_of_its_tier() {
+        let mut tiers = TrackerTiers::new("", &[]);
+        tiers.tiers = vec![
+            strings(&["http://a/", "http://b/", "http://c/"]),
+            strings(&["http://d/", "http://e/"]),
+        ];
+        assert_eq!(tiers.promote(0, 2), "http://c/");
+        assert_eq!(tiers.promote(1, 1), "http://e/");
+        assert_eq!(
+            tiers.tiers(),
+            [
+                strings(&["http://c/", "http://a/", "http://b/"]),
+                strings(&["http://e/", "http://d/"]),
+            ]
+        );
+        assert_eq!(tiers.promote(0, 0), "http://c/");
+        assert_eq!(
+            tiers.tiers()[0],
+            strings(&["http://c/", "http://a/", "http://b/"])
+        );
+    }
+
+    #[test]
+    fn scrape_url_replaces_announce_segment() {
+        let cases = [
+            ("http://example.com/announce", "http://example.com/scrape"),
+            (
+                "http://example.com/x/announce",
+                "http://example.com/x/scrape",
+            ),
+            (
+                "http://example.com/announce.php",
+                "http://example.com/scrape.php",
+            ),
+            (
+                "http://example.com/announce?x2%0644",
+                "http://example.com/scrape?x2%0644",
+            ),
+            (
+                "https://example.com/announce?x=2/4",
+                "https://example.com/scrape?x=2/4",
+            ),
+            (
+                "http://example.com:6969/abc123/announce",
+                "http://example.com:6969/abc123/scrape",
+            ),
+        ];
+        for (announce, scrape) in cases {
+            assert_eq!(scrape_url(announce), Ok(scrape.to_string()), "{}", announce);
+        }
+    }
+
+    #[test]
+    fn scrape_url_rejects_other_paths() {
+        for announce in [
+            "http://example.com/a",
+            "http://example.com/x%064announce",
+            "http://example.com/announce/",
+            "http://announce",
+            "http://example.com",
+            "wss://example.com/announce",
+        ] {
+            assert_eq!(
+                scrape_url(announce),
+                Err(ScrapeUnsupported(announce.to_string())),
+                "{}",
+                announce
             );
-            Err(anyhow!("error deserializing tracker response: {}", e))
         }
     }
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn filter_peers_drops_unusable_addresses() {
+        let peers = addrs(&[
+            "192.0.2.1:6881",
+            "192.0.2.1:6881",
+            "203.0.113.9:6881",
+            "192.0.2.2:0",
+            "0.0.0.0:6881",
+            "224.0.0.1:6881",
+            "255.255.255.255:6881",
+            "240.0.0.1:6881",
+            "[::]:6881",
+            "[ff02::1]:6881",
+            "192.0.2.1:6882",
+            "[2001:db8::1]:6881",
+        ]);
+        let (kept, discarded) = filter_peers(peers, Some("203.0.113.9:6881".parse().unwrap()));
+        assert_eq!(
+            kept,
+            addrs(&["192.0.2.1:6881", "192.0.2.1:6882", "[2001:db8::1]:6881"])
+        );
+        assert_eq!(discarded.duplicate, 1);
+        assert_eq!(discarded.ourselves, 1);
+        assert_eq!(discarded.port_zero, 1);
+        assert_eq!(discarded.bogus, 6);
+        assert_eq!(discarded.total(), 9);
+    }
+
+    #[test]
+    fn filter_peers_keeps_our_ip_on_another_port() {
+        let peers = addrs(&["203.0.113.9:6881", "203.0.113.9:51413"]);
+        let (kept, discarded) = filter_peers(peers, Some("203.0.113.9:6881".parse().unwrap()));
+        assert_eq!(kept, addrs(&["203.0.113.9:51413"]));
+        assert_eq!(discarded.ourselves, 1);
+
+        let peers = addrs(&["203.0.113.9:6881"]);
+        let (kept, discarded) = filter_peers(peers.clone(), None);
+        assert_eq!(kept, peers);
+        assert_eq!(discarded.total(), 0);
+    }
+
+    #[test]
+    fn scrape_url_keeps_udp_trackers() {
+        assert_eq!(
+            scrape_url("udp://tracker.example.com:1337/announce"),
+            Ok("udp://tracker.example.com:1337/announce".to_string())
+        );
+    }
 }
@@ -0,0 +1,150 @@
+// BEP 15 UDP tracker protocol
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio::{net::UdpSocket, time::timeout};
+
+use super::{compact_peers, filter_peers, Announce, AnnounceEvent, Progress, LISTEN_PORT};
+use crate::{error::Error, utils::random_u32};
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+// BEP 15 waits 15 * 2^n seconds for the nth attempt; we give up sooner than
+// the spec's 8 retries since nobody waits an hour at a prompt
+const FIRST_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_TRIES: u32 = 3;
+
+pub async fn announce(
+    tracker_addr: &str,
+    progress: Progress,
+    infohash: [u8; 20],
+    my_peer_id: [u8; 20],
+    local_addr: Option<IpAddr>,
+) -> anyhow::Result<Announce> {
+    let remote = resolve(tracker_addr).await?;
+    let local = local_addr.unwrap_or(if remote.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    });
+    let sock = UdpSocket::bind(SocketAddr::new(local, 0))
+        .await
+        .map_err(Error::Io)?;
+    sock.connect(remote).await.map_err(Error::Io)?;
+
+    let mut connect = Vec::with_capacity(16);
+    connect.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    connect.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    connect.extend_from_slice(&[0; 4]); // transaction id, filled in by transact
+    let resp = transact(&sock, connect, ACTION_CONNECT, 16).await?;
+    let connection_id = &resp[8..16];
+
+    let mut req = Vec::with_capacity(98);
+    req.extend_from_slice(connection_id);
+    req.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    req.extend_from_slice(&[0; 4]); // transaction id, filled in by transact
+    req.extend_from_slice(&infohash);
+    req.extend_from_slice(&my_peer_id);
+    req.extend_from_slice(&progress.downloaded.to_be_bytes());
+    req.extend_from_slice(&progress.left.to_be_bytes());
+    req.extend_from_slice(&progress.uploaded.to_be_bytes());
+    let event: u32 = match progress.event {
+        None => 0,
+        Some(AnnounceEvent::Completed) => 1,
+        Some(AnnounceEvent::Started) => 2,
+        Some(AnnounceEvent::Stopped) => 3,
+    };
+    req.extend_from_slice(&event.to_be_bytes());
+    req.extend_from_slice(&0u32.to_be_bytes()); // ip: the sender's
+    req.extend_from_slice(&random_u32().to_be_bytes()); // key
+    req.extend_from_slice(&(-1i32).to_be_bytes()); // num_want: default
+    req.extend_from_slice(&LISTEN_PORT.to_be_bytes());
+    let resp = transact(&sock, req, ACTION_ANNOUNCE, 20).await?;
+
+    // the peer list's address family follows the socket's
+    let peers = if remote.is_ipv4() {
+        compact_peers(&resp[20..], &[])
+    } else {
+        compact_peers(&[], &resp[20..])
+    };
+    let (peers, discarded) = filter_peers(peers, None);
+    Ok(Announce {
+        peers,
+        interval: Duration::from_secs(
+            u32::from_be_bytes([resp[8], resp[9], resp[10], resp[11]]).into(),
+        ),
+        min_interval: None,
+        external_ip: None,
+        discarded,
+        tracker_id: None,
+    })
+}
+
+async fn resolve(tracker_addr: &str) -> anyhow::Result<SocketAddr> {
+    let host_port = tracker_addr
+        .strip_prefix("udp://")
+        .and_then(|rest| rest.split('/').next())
+        .filter(|hp| !hp.is_empty())
+        .ok_or_else(|| Error::Usage(format!("invalid UDP tracker URL {:?}", tracker_addr)))?;
+    tokio::net::lookup_host(host_port)
+        .await
+        .with_context(|| format!("failed to resolve {}", host_port))?
+        .next()
+        .with_context(|| format!("{} has no addresses", host_port))
+}
+
+// sends `req` with a fresh transaction id in bytes 12..16 (both request kinds
+// put it there), retrying with backoff until a response to it arrives. returns
+// the response once it's known to be `action` and at least `min_len` long.
+async fn transact(
+    sock: &UdpSocket,
+    mut req: Vec<u8>,
+    action: u32,
+    min_len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let txid = random_u32().to_be_bytes();
+    req[12..16].copy_from_slice(&txid);
+    let mut buf = vec![0u8; 65536];
+
+    for attempt in 0..MAX_TRIES {
+        sock.send(&req).await.map_err(Error::Io)?;
+        let wait = FIRST_TIMEOUT * 2u32.pow(attempt);
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            let n = match timeout(
+                deadline.saturating_duration_since(tokio::time::Instant::now()),
+                sock.recv(&mut buf),
+            )
+            .await
+            {
+                Ok(n) => n.map_err(Error::Io)?,
+                Err(_) => break, // timed out, send again
+            };
+            let resp = &buf[..n];
+            // stray or late replies to another transaction are ignored
+            if n < 8 || resp[4..8] != txid {
+                continue;
+            }
+            let got = u32::from_be_bytes([resp[0], resp[1], resp[2], resp[3]]);
+            if got == ACTION_ERROR {
+                let msg = String::from_utf8_lossy(&resp[8..]).to_string();
+                return Err(Error::Tracker(msg).into());
+            }
+            if got != action || n < min_len {
+                return Err(Error::Tracker(format!(
+                    "malformed response: action {}, {} bytes",
+                    got, n
+                ))
+                .into());
+            }
+            return Ok(resp.to_vec());
+        }
+        eprintln!("no response from UDP tracker after {:?}, retrying", wait);
+    }
+    Err(Error::Tracker(format!("no response after {} attempts", MAX_TRIES)).into())
+}
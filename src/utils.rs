@@ -1,6 +1,47 @@
+use std::{
+    arch::x86_64::_rdrand32_step,
+    env,
+    ffi::OsString,
+    fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+
+pub const BOLD: &str = "1";
+pub const RED: &str = "31";
+pub const GREEN: &str = "32";
+
+// color only when writing to a terminal, and neither --no-color nor a
+// non-empty NO_COLOR (https://no-color.org) asks otherwise
+pub fn color_enabled<S: IsTerminal>(stream: S, args: &[String]) -> bool {
+    stream.is_terminal()
+        && !args.iter().any(|a| a == "--no-color")
+        && env::var_os("NO_COLOR").map_or(true, |v| v.is_empty())
+}
+
+pub fn random_u32() -> u32 {
+    let mut val = 0;
+    unsafe {
+        _rdrand32_step(&mut val);
+    }
+    val
+}
+
+pub fn paint(text: &str, sgr: &str, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{}m{}\x1b[0m", sgr, text)
+    } else {
+        text.to_string()
+    }
+}
+
 pub fn convert_bencode_to_json(
     value: serde_bencode::value::Value,
-) -> anyhow::Result<serde_json::Value> {
+) -> crate::error::Result<serde_json::Value> {
     match value {
         serde_bencode::value::Value::Bytes(b) => {
             let stringified = String::from_utf8_lossy(&b);
@@ -8,22 +49,23 @@ pub fn convert_bencode_to_json(
         }
         serde_bencode::value::Value::Int(i) => Ok(serde_json::Value::Number(i.into())),
         serde_bencode::value::Value::List(l) => Ok(serde_json::Value::Array(
-            l.iter()
-                .map(|v| convert_bencode_to_json(v.clone()).expect("failed conversion"))
-                .collect(),
+            l.into_iter()
+                .map(convert_bencode_to_json)
+                .collect::<crate::error::Result<_>>()?,
         )),
         serde_bencode::value::Value::Dict(d) => {
-            Ok(serde_json::map::Map::from_iter(d.iter().map(|(k, v)| {
-                let key = String::from_utf8(k.to_vec()).expect("dict keys must be utf-8");
-                let val = convert_bencode_to_json(v.clone()).expect("failed converting dict value");
-                (key, val)
-            }))
-            .into())
+            let mut map = serde_json::map::Map::new();
+            for (k, v) in d {
+                let key = String::from_utf8(k).map_err(|e| {
+                    crate::error::Error::Bencode(format!("dict key is not utf-8: {}", e))
+                })?;
+                map.insert(key, convert_bencode_to_json(v)?);
+            }
+            Ok(map.into())
         }
     }
 }
 
-#[allow(dead_code)]
 pub fn hexedit<T: AsRef<[u8]>>(data: T) -> String {
     data.as_ref()
         .chunks(16)
@@ -58,3 +100,115 @@ pub fn hexedit<T: AsRef<[u8]>>(data: T) -> String {
         })
         .collect()
 }
+
+// advisory lock on a file being written, held as a "<target>.lock" file that
+// records our pid and is removed again on drop
+pub struct TargetLock {
+    path: PathBuf,
+}
+
+// a lock file with no pid in it yet is taken to be mid-write for this long
+const LOCK_WRITE_GRACE: Duration = Duration::from_secs(5);
+
+impl TargetLock {
+    pub fn acquire<P: AsRef<Path>>(target: P) -> anyhow::Result<Self> {
+        let target = target.as_ref();
+        let mut path = OsString::from(target.as_os_str());
+        path.push(".lock");
+        let path = PathBuf::from(path);
+
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut f) => {
+                    write!(f, "{}", process::id()).context("failed writing lock file")?;
+                    return Ok(TargetLock { path });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let holder = fs::read_to_string(&path).unwrap_or_default();
+                    match holder.trim().parse::<u32>() {
+                        Ok(pid) if process_alive(pid) => {
+                            bail!("{} is already in use by PID {}", target.display(), pid)
+                        }
+                        Ok(pid) => eprintln!(
+                            "taking over {}, left behind by PID {} which is gone",
+                            path.display(),
+                            pid
+                        ),
+                        Err(_) if !lock_abandoned(&path) => {
+                            bail!("{} is being locked by another process", target.display())
+                        }
+                        Err(_) => eprintln!("taking over {}, which has no PID", path.display()),
+                    }
+                    // whoever gets to create it afresh has it
+                    match fs::remove_file(&path) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(e).context("failed removing stale lock file"),
+                    }
+                }
+                Err(e) => return Err(e).context("failed creating lock file"),
+            }
+        }
+    }
+}
+
+fn process_alive(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+// a lock file still without a pid long after it was created
+fn lock_abandoned(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .map_or(true, |age| age > LOCK_WRITE_GRACE)
+}
+
+impl Drop for TargetLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_lock_takes_over_only_stale_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.bin");
+        let lock_path = dir.path().join("out.bin.lock");
+
+        let held = TargetLock::acquire(&target).unwrap();
+        let err = TargetLock::acquire(&target).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} is already in use by PID {}",
+                target.display(),
+                process::id()
+            )
+        );
+        drop(held);
+        assert!(!lock_path.exists());
+
+        // pids never go this high, so no such process is running
+        fs::write(&lock_path, u32::MAX.to_string()).unwrap();
+        let taken = TargetLock::acquire(&target).unwrap();
+        assert_eq!(
+            fs::read_to_string(&lock_path).unwrap(),
+            process::id().to_string()
+        );
+        drop(taken);
+
+        // an empty lock file is somebody else still writing theirs
+        fs::write(&lock_path, "").unwrap();
+        assert!(TargetLock::acquire(&target).is_err());
+    }
+}